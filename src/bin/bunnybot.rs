@@ -1,28 +1,150 @@
 #![recursion_limit = "1024"]
 
+use bunnybot::config::Config;
 use bunnybot::errors::*;
 use bunnybot::git;
 use bunnybot::launchpad;
 use bunnybot::launchpad::Credentials;
+use bunnybot::maintenance;
 use bunnybot::pidfile::Pidfile;
-use bunnybot::subprocess::{run_command, Verbose};
-use error_chain::quick_main;
+use bunnybot::revision_map::RevisionMap;
+use bunnybot::subprocess;
+use chrono::prelude::*;
+use error_chain::{bail, quick_main};
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use structopt::StructOpt;
 
+const TRUNK: &str = "~widelands-dev/widelands/trunk";
+
 lazy_static! {
-    static ref MERGE_REGEX: Regex = Regex::new(r"(?im)^@bunnybot.*merge").unwrap();
-    static ref MERGE_FORCE_REGEX: Regex = Regex::new(r"(?im)^@bunnybot.*merge force").unwrap();
+    // The command must directly follow the mention, so that it is not picked out of a sentence
+    // like "@bunnybot what's the merge status?".
+    static ref MERGE_REGEX: Regex = Regex::new(r"(?im)^@bunnybot\s+merge\b").unwrap();
+    static ref MERGE_FORCE_REGEX: Regex =
+        Regex::new(r"(?im)^@bunnybot\s+merge\s+force\b").unwrap();
+    static ref UPDATE_REGEX: Regex = Regex::new(r"(?im)^@bunnybot\s+update\b").unwrap();
+    static ref FIX_FORMATTING_REGEX: Regex =
+        Regex::new(r"(?im)^@bunnybot\s+fix\s+formatting\b").unwrap();
+    static ref STATUS_REGEX: Regex = Regex::new(r"(?im)^@bunnybot\s+status\b").unwrap();
+    static ref MIRROR_REGEX: Regex = Regex::new(r"(?im)^@bunnybot\s+mirror\b").unwrap();
+    static ref REVERT_REGEX: Regex = Regex::new(r"(?im)^@bunnybot\s+revert\b").unwrap();
+    static ref MERGE_PRIORITY_REGEX: Regex =
+        Regex::new(r"(?im)^@bunnybot\s+merge\b.*\bpriority\s+(-?\d+)").unwrap();
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Merge {
+        force: bool,
+        priority: i32,
+        commit_message: Option<String>,
+    },
+    Update,
+    FixFormatting,
+    Status,
+    Mirror,
+    Revert,
+}
+
+impl Command {
+    fn parse(message_body: &str) -> Option<Self> {
+        let priority = MERGE_PRIORITY_REGEX
+            .captures(message_body)
+            .map(|c| c[1].parse().unwrap_or(0))
+            .unwrap_or(0);
+        if let Some(m) = MERGE_FORCE_REGEX.find(message_body) {
+            Some(Command::Merge {
+                force: true,
+                priority,
+                commit_message: fenced_block_after_line(message_body, m.end()),
+            })
+        } else if let Some(m) = MERGE_REGEX.find(message_body) {
+            Some(Command::Merge {
+                force: false,
+                priority,
+                commit_message: fenced_block_after_line(message_body, m.end()),
+            })
+        } else if UPDATE_REGEX.find(message_body).is_some() {
+            Some(Command::Update)
+        } else if FIX_FORMATTING_REGEX.find(message_body).is_some() {
+            Some(Command::FixFormatting)
+        } else if STATUS_REGEX.find(message_body).is_some() {
+            Some(Command::Status)
+        } else if MIRROR_REGEX.find(message_body).is_some() {
+            Some(Command::Mirror)
+        } else if REVERT_REGEX.find(message_body).is_some() {
+            Some(Command::Revert)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the contents of the block fenced by ``` that follows the line containing 'pos'. This is
+/// how users pass a commit message along with their merge command. Any other text after the
+/// command, e.g. a remark or a signature, is not meant for the history and is ignored.
+fn fenced_block_after_line(message_body: &str, pos: usize) -> Option<String> {
+    let rest = &message_body[pos..];
+    let rest = rest[rest.find('\n')? + 1..].trim_start();
+    if !rest.starts_with("```") {
+        return None;
+    }
+    let rest = &rest[3..];
+    let text = rest[..rest.find("```")?].trim();
+    if text.is_empty() {
+        return None;
+    }
+    Some(text.to_string())
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Command::Merge { force: true, .. } => write!(f, "merge force"),
+            Command::Merge { force: false, .. } => write!(f, "merge"),
+            Command::Update => write!(f, "update"),
+            Command::FixFormatting => write!(f, "fix formatting"),
+            Command::Status => write!(f, "status"),
+            Command::Mirror => write!(f, "mirror"),
+            Command::Revert => write!(f, "revert"),
+        }
+    }
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, Default, Clone)]
 struct BranchState {
     appveyor_state: launchpad::CiState,
     travis_state: launchpad::CiState,
+    // Revision id of the tip that was last mirrored to git.
+    #[serde(default)]
+    revision_id: Option<String>,
+    // Revision id of the tip the CI states were recorded for.
+    #[serde(default)]
+    ci_revision_id: Option<String>,
+}
+
+// The most recent failure for a proposal. Cleared again when a later command succeeds.
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+struct Failure {
+    action: String,
+    time: String,
+    message: String,
+}
+
+impl Failure {
+    fn new(action: &str, err: &Error) -> Self {
+        Failure {
+            action: action.to_string(),
+            time: Utc::now().to_rfc3339(),
+            message: err.to_string(),
+        }
+    }
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
@@ -30,12 +152,101 @@ struct MergeProposalState {
     num_comments: usize,
     source_branch: String,
     target_branch: String,
+    // Replaces the undated 'last_error' string of older states, which is ignored when loading.
+    #[serde(default)]
+    last_failure: Option<Failure>,
+    // Comment links of all commands that we already acknowledged.
+    #[serde(default)]
+    acknowledged_commands: Vec<String>,
+    // Paths that conflicted when last test merging into the target. Empty if the merge was clean.
+    #[serde(default)]
+    conflicts: Vec<String>,
+}
+
+impl MergeProposalState {
+    /// Returns the index of the first comment that was not yet processed.
+    fn first_unprocessed_comment(&self, mp: &launchpad::MergeProposal) -> usize {
+        if self.num_comments <= mp.comments.len() {
+            self.num_comments
+        } else {
+            println!("Number of comment s decreased. Probably a reopened PR. Forgetting state.");
+            0
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq)]
+enum MergeOutcome {
+    Merged,
+    Refused(String),
+    Failed(String),
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+struct MergeQueueEntry {
+    source_branch: String,
+    target_branch: String,
+    // Link and number of the comment that requested the merge.
+    comment: String,
+    comment_number: usize,
+    requested_at: String,
+    priority: i32,
+    force: bool,
+    commit_message: Option<String>,
+    // Revision id of the source tip the merge was requested for. Only this revision is merged.
+    #[serde(default)]
+    revision_id: Option<String>,
+    // None while the entry is waiting to be merged.
+    outcome: Option<MergeOutcome>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+struct MergeRecord {
+    source_branch: String,
+    target_branch: String,
+    // API link of the merge proposal, so that we can still read its comments once it is merged.
+    merge_proposal: String,
+    // Number of comments at the time of the merge. Only later comments can request a revert.
+    num_comments: usize,
+    revision: launchpad::Revision,
+    merged_at: String,
+    reverted: bool,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
+struct Unmentioned {
+    // When we first noticed, in RFC 3339.
+    since: String,
+    // Number of consecutive runs that did not mention the branch.
+    runs: u32,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
 struct State {
     branches: HashMap<String, BranchState>,
     merge_proposals: Vec<MergeProposalState>,
+    #[serde(default)]
+    merge_queue: Vec<MergeQueueEntry>,
+    // Revision id of trunk that all proposals were last checked for conflicts against. Revnos can
+    // repeat after an overwriting push.
+    #[serde(default)]
+    conflicts_checked_revision_id: Option<String>,
+    // Every merge done by bunnybot, oldest first. Not tied to open proposals, so it survives
+    // 'remove_mentions_of'.
+    #[serde(default)]
+    merge_history: Vec<MergeRecord>,
+    // SHAs we last pushed to the GitHub mirror, by branch name.
+    #[serde(default)]
+    pushed_shas: launchpad::Leases,
+    // Version of the scheme the slugs in the data dir were created with.
+    #[serde(default)]
+    slug_version: u32,
+    // Branches that are not mentioned by any proposal anymore, by slug.
+    #[serde(default)]
+    unmentioned: HashMap<String, Unmentioned>,
+    // When maintenance last ran, in RFC 3339.
+    #[serde(default)]
+    last_maintenance: Option<String>,
 }
 
 impl State {
@@ -53,6 +264,30 @@ impl State {
         Ok(())
     }
 
+    /// Slugs of all branches we keep state for.
+    fn slugs(&self) -> HashSet<String> {
+        let mut slugs = self
+            .branches
+            .keys()
+            .map(|k| launchpad::slugify(&k))
+            .collect::<HashSet<_>>();
+        for proposal in &self.merge_proposals {
+            slugs.insert(launchpad::slugify(&proposal.source_branch));
+            slugs.insert(launchpad::slugify(&proposal.target_branch));
+        }
+        slugs
+    }
+
+    pub fn find_merge_proposal_state(
+        &self,
+        mp: &launchpad::MergeProposal,
+    ) -> Option<&MergeProposalState> {
+        self.merge_proposals.iter().find(|item| {
+            item.source_branch == mp.source_branch.unique_name
+                && item.target_branch == mp.target_branch.unique_name
+        })
+    }
+
     pub fn find_or_insert_merge_proposal_state(
         &mut self,
         mp: &launchpad::MergeProposal,
@@ -71,40 +306,184 @@ impl State {
                 num_comments: 0,
                 source_branch: mp.source_branch.unique_name.clone(),
                 target_branch: mp.target_branch.unique_name.clone(),
+                last_failure: None,
+                acknowledged_commands: Vec::new(),
+                conflicts: Vec::new(),
             });
             index = Some(self.merge_proposals.len() - 1);
         }
         self.merge_proposals.get_mut(index.unwrap()).unwrap()
     }
 
+    /// Returns the indices of all queue entries that still wait to be merged, in the order in
+    /// which they should be merged.
+    pub fn pending_merges(&self) -> Vec<usize> {
+        let mut pending = (0..self.merge_queue.len())
+            .filter(|&idx| self.merge_queue[idx].outcome.is_none())
+            .collect::<Vec<_>>();
+        pending.sort_by(|&a, &b| {
+            let a = &self.merge_queue[a];
+            let b = &self.merge_queue[b];
+            b.priority
+                .cmp(&a.priority)
+                .then_with(|| a.requested_at.cmp(&b.requested_at))
+        });
+        pending
+    }
+
+    /// Forgets queue entries that were handled more than 'max_age_days' ago.
+    pub fn prune_merge_queue(&mut self, max_age_days: i64) {
+        let now = Utc::now();
+        self.merge_queue.retain(|entry| {
+            if entry.outcome.is_none() {
+                return true;
+            }
+            match DateTime::parse_from_rfc3339(&entry.requested_at) {
+                Ok(requested_at) => {
+                    now.signed_duration_since(requested_at).num_days() < max_age_days
+                }
+                Err(_) => true,
+            }
+        });
+    }
+
     pub fn remove_mentions_of(&mut self, slug: &str) {
         self.merge_proposals
             .retain(|m| launchpad::slugify(&m.source_branch) != slug);
+        self.merge_queue
+            .retain(|e| launchpad::slugify(&e.source_branch) != slug);
         let new_branches = self
             .branches
             .drain()
             .filter(|&(ref k, _)| launchpad::slugify(&k) != slug)
             .collect();
         self.branches = new_branches;
+        self.pushed_shas.remove(slug);
     }
 }
 
+/// Namespace on GitHub for branches that were deleted. Below it are the slug and the date of
+/// deletion in 'ARCHIVE_DATE' format.
+const ARCHIVE_PREFIX: &str = "refs/archive/";
+const ARCHIVE_DATE: &str = "%Y-%m-%d-%H%M%S";
+
+/// Lists the archived branches on GitHub. If 'prune_days' is set, archives older than that are
+/// deleted instead.
+fn manage_archive(git_repo: &Path, prune_days: Option<i64>) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    for reference in git::remote_refs(git_repo, "github", ARCHIVE_PREFIX)? {
+        // The date is the last component, slugs never contain a '/'.
+        let date = reference.rsplit('/').next().unwrap();
+        let archived_at = match NaiveDateTime::parse_from_str(date, ARCHIVE_DATE) {
+            Ok(archived_at) => archived_at,
+            Err(_) => {
+                println!("Ignoring {}, which has no valid date.", reference);
+                continue;
+            }
+        };
+        let age_days = now.signed_duration_since(archived_at).num_days();
+        match prune_days {
+            Some(days) if age_days >= days => {
+                println!("Deleting {} ({} days old).", reference, age_days);
+                git::delete_remote_ref(git_repo, "github", &reference)?;
+            }
+            Some(_) => (),
+            None => println!("{} ({} days old)", reference, age_days),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the slugs of the branches that must never be deleted: trunk and the protected ones of
+/// the config.
+fn protected_slugs(config: &Config) -> HashSet<String> {
+    let mut protected = config
+        .deletion
+        .protected
+        .iter()
+        .map(|name| launchpad::slugify(name))
+        .collect::<HashSet<_>>();
+    protected.insert(launchpad::slugify(TRUNK));
+    protected
+}
+
+/// Deletes branches that have not been mentioned by any proposal for the grace period of the
+/// config. A branch that is mentioned again is safe until it becomes unmentioned again.
 fn delete_unmentioned_branches(
     slugs: &HashSet<String>,
+    config: &Config,
     state: &mut State,
     bzr_repo: &Path,
     git_repo: &Path,
 ) -> Result<()> {
-    let mut checked_out_branches = HashSet::new();
+    let mut candidates = state.slugs();
     for path in fs::read_dir(bzr_repo).unwrap() {
         let path = path.unwrap().path();
-        if !path.is_dir() || path.file_name().unwrap() == ".bzr" {
+        // Skip .bzr and other hidden directories, like the scratch space for test merges.
+        if !path.is_dir() || path.file_name().unwrap().to_string_lossy().starts_with('.') {
             continue;
         }
-        checked_out_branches.insert(path.file_name().unwrap().to_string_lossy().to_string());
+        candidates.insert(path.file_name().unwrap().to_string_lossy().to_string());
     }
+    let deletion = &config.deletion;
+    let protected = protected_slugs(config);
+    let candidates = candidates
+        .into_iter()
+        .filter(|slug| !slugs.contains(slug) && !protected.contains(slug))
+        .collect::<HashSet<_>>();
 
-    for slug in checked_out_branches.difference(&slugs) {
+    state
+        .unmentioned
+        .retain(|slug, _| candidates.contains(slug));
+    let now = Utc::now();
+    let mut due = Vec::new();
+    for slug in &candidates {
+        let unmentioned = state
+            .unmentioned
+            .entry(slug.clone())
+            .or_insert_with(|| Unmentioned {
+                since: now.to_rfc3339(),
+                runs: 0,
+            });
+        unmentioned.runs += 1;
+        let hours = DateTime::parse_from_rfc3339(&unmentioned.since)
+            .map(|since| now.signed_duration_since(since).num_hours())
+            .unwrap_or(0);
+        let hours_over = deletion
+            .grace_hours
+            .map(|grace_hours| hours >= grace_hours as i64)
+            .unwrap_or(false);
+        if unmentioned.runs >= deletion.grace_runs || hours_over {
+            due.push(slug.clone());
+        } else {
+            println!(
+                "{} is not mentioned anymore since {} ({} runs).",
+                slug, unmentioned.since, unmentioned.runs
+            );
+        }
+    }
+    due.sort();
+
+    if due.len() > deletion.max_per_run {
+        bail!(
+            "Refusing to delete {} branches at once, the limit is {}: {}",
+            due.len(),
+            deletion.max_per_run,
+            due.join(", ")
+        );
+    }
+    for slug in &due {
+        if deletion.dry_run {
+            println!("Would delete {} which is not mentioned anymore.", slug);
+            continue;
+        }
+        if deletion.archive {
+            let archive_ref = format!("{}{}/{}", ARCHIVE_PREFIX, slug, now.format(ARCHIVE_DATE));
+            if let Err(err) = git::archive_remote_branch(git_repo, "github", slug, &archive_ref) {
+                println!("Not deleting {}, archiving it failed: {}", slug, err);
+                continue;
+            }
+        }
         println!("Deleting {} which is not mentioned anymore.", slug);
 
         // Ignore errors - most likely some branches where not really there.
@@ -112,24 +491,13 @@ fn delete_unmentioned_branches(
             .map_err(|err| println!("Ignored error while deleting remote branch: {}", err));
         let _ = git::delete_local_branch(git_repo, slug)
             .map_err(|err| println!("Ignored error while deleting local branch: {}", err));
-        let _ = fs::remove_dir_all(&bzr_repo.join(slug))
-            .map_err(|err| println!("Ignored error while deleting bzr dir: {}", err));
-        state.remove_mentions_of(&slug);
-    }
-
-    let mut state_slugs = state
-        .branches
-        .keys()
-        .map(|k| launchpad::slugify(&k))
-        .collect::<HashSet<_>>();
-    for proposal in &state.merge_proposals {
-        state_slugs.insert(launchpad::slugify(&proposal.source_branch));
-        state_slugs.insert(launchpad::slugify(&proposal.target_branch));
-    }
-
-    for slug in state_slugs.difference(&slugs) {
-        println!("Deleting {} which is not mentioned anymore.", slug);
+        let path = bzr_repo.join(slug);
+        if path.exists() {
+            let _ = fs::remove_dir_all(&path)
+                .map_err(|err| println!("Ignored error while deleting bzr dir: {}", err));
+        }
         state.remove_mentions_of(&slug);
+        state.unmentioned.remove(slug);
     }
 
     Ok(())
@@ -149,6 +517,103 @@ fn build_ci_state_update(
     comment
 }
 
+/// Returns a description of every check that currently prevents a merge without force.
+fn failing_merge_checks(travis_state: &launchpad::CiState) -> Vec<String> {
+    let mut failures = Vec::new();
+    if travis_state.state != "passed" {
+        failures.push(format!(
+            "Travis is not green (build {}, state: {}).",
+            travis_state.number, travis_state.state
+        ));
+    }
+    failures
+}
+
+fn build_status_comment(
+    m: &launchpad::MergeProposal,
+    state: &State,
+    git_repo: &Path,
+    pending_commands: &[Command],
+) -> String {
+    let mut comment = String::new();
+    comment.push_str("This is what bunnybot knows about this proposal:\n\n");
+    match git::rev_parse(git_repo, &m.source_branch.slug) {
+        Ok(sha) => comment.push_str(&format!(
+            "Git mirror: branch {} at {}.\n",
+            m.source_branch.slug, sha
+        )),
+        Err(_) => comment.push_str(&format!(
+            "Git mirror: branch {} is not mirrored yet.\n",
+            m.source_branch.slug
+        )),
+    }
+
+    let branch_state = state
+        .branches
+        .get(&m.source_branch.unique_name)
+        .cloned()
+        .unwrap_or_default();
+    comment.push_str(&format!(
+        "Travis build {}. State: {}.\n",
+        branch_state.travis_state.number, branch_state.travis_state.state
+    ));
+    comment.push_str(&format!(
+        "Appveyor build {}. State: {}.\n",
+        branch_state.appveyor_state.number, branch_state.appveyor_state.state
+    ));
+
+    let queued_merges = state
+        .pending_merges()
+        .into_iter()
+        .enumerate()
+        .filter(|&(_, idx)| {
+            let entry = &state.merge_queue[idx];
+            entry.source_branch == m.source_branch.unique_name
+                && entry.target_branch == m.target_branch.unique_name
+        })
+        .map(|(position, idx)| (position, &state.merge_queue[idx]))
+        .collect::<Vec<_>>();
+    comment.push_str("\nPending commands:");
+    if pending_commands.is_empty() && queued_merges.is_empty() {
+        comment.push_str(" none\n");
+    } else {
+        comment.push('\n');
+        for (position, entry) in queued_merges {
+            comment.push_str(&format!(
+                "- merge requested in comment #{}, position {} in the merge queue\n",
+                entry.comment_number,
+                position + 1
+            ));
+        }
+        for command in pending_commands {
+            comment.push_str(&format!("- @bunnybot {}\n", command));
+        }
+    }
+
+    comment.push_str("\nFailing merge checks:");
+    let failures = failing_merge_checks(&branch_state.travis_state);
+    if failures.is_empty() {
+        comment.push_str(" none\n");
+    } else {
+        comment.push('\n');
+        for failure in failures {
+            comment.push_str(&format!("- {}\n", failure));
+        }
+    }
+
+    let last_failure = state
+        .find_merge_proposal_state(m)
+        .and_then(|p| p.last_failure.clone());
+    match last_failure {
+        Some(failure) => comment.push_str(&format!(
+            "\nLast error while {} at {}:\n\n{}",
+            failure.action, failure.time, failure.message
+        )),
+        None => comment.push_str("\nLast error: none"),
+    }
+    comment
+}
+
 fn build_refuse_merge_comment(travis_state: &launchpad::CiState) -> String {
     let mut comment = String::new();
     comment.push_str("Refusing to merge, since Travis is not green. Use @bunnybot merge force for merging anyways.\n");
@@ -160,30 +625,448 @@ fn build_refuse_merge_comment(travis_state: &launchpad::CiState) -> String {
     comment
 }
 
-fn build_error_report_comment(err: &Error) -> String {
-    format!("Error merging this proposal:\n\n{}", err)
+fn build_branch_changed_comment(m: &launchpad::MergeProposal) -> String {
+    format!(
+        "Refusing to merge, since lp:{} changed after the merge was requested. Please have a look \
+         at the new commits and request the merge again.",
+        m.source_branch.unique_name
+    )
 }
 
-fn update_git_master(bzr_repo: &Path, git_repo: &Path) -> Result<()> {
-    let trunk = launchpad::Branch::from_unique_name("~widelands-dev/widelands/trunk");
-    trunk.update(bzr_repo)?;
-    trunk.update_git(git_repo)?;
-
-    // Merge trunk into master and push to github.
-    git::checkout_branch(git_repo, "master")?;
-    run_command(
-        &["git", "merge", "--ff-only", &trunk.slug],
-        git_repo,
-        Verbose::Yes,
-    )?;
-    run_command(
-        &["git", "push", "github", "master", "--force"],
-        git_repo,
-        Verbose::Yes,
+#[derive(Debug, Clone, Copy)]
+enum Acknowledgement {
+    Queued,
+    WaitingForCi,
+    Executing,
+}
+
+fn build_acknowledgement_comment(
+    command: &Command,
+    comment_number: usize,
+    acknowledgement: Acknowledgement,
+) -> String {
+    let what = match acknowledgement {
+        Acknowledgement::Queued => "Queued",
+        Acknowledgement::WaitingForCi => "Waiting for the continuous integration builds to finish",
+        Acknowledgement::Executing => "Executing",
+    };
+    format!(
+        "{} @bunnybot {} (requested in comment #{}).",
+        what, command, comment_number
+    )
+}
+
+/// Posts 'acknowledgement' for the command in comment 'comment_index' unless this command was
+/// already acknowledged before.
+fn acknowledge_command(
+    m: &launchpad::MergeProposal,
+    credentials: &Credentials,
+    state: &mut State,
+    comment_index: usize,
+    command: &Command,
+    acknowledgement: Acknowledgement,
+) -> Result<()> {
+    let link = &m.comments[comment_index].self_link;
+    if state
+        .find_or_insert_merge_proposal_state(m)
+        .acknowledged_commands
+        .contains(link)
+    {
+        return Ok(());
+    }
+    m.add_comment(
+        credentials,
+        &build_acknowledgement_comment(command, comment_index + 1, acknowledgement),
     )?;
+    mark_command_acknowledged(m, state, comment_index);
+    Ok(())
+}
+
+fn mark_command_acknowledged(
+    m: &launchpad::MergeProposal,
+    state: &mut State,
+    comment_index: usize,
+) {
+    let link = &m.comments[comment_index].self_link;
+    let acknowledged_commands = &mut state
+        .find_or_insert_merge_proposal_state(m)
+        .acknowledged_commands;
+    if !acknowledged_commands.contains(link) {
+        acknowledged_commands.push(link.clone());
+    }
+}
+
+/// Acknowledges all commands that are not yet processed because we are waiting for CI.
+fn acknowledge_waiting_commands(
+    m: &launchpad::MergeProposal,
+    credentials: &Credentials,
+    state: &mut State,
+) -> Result<()> {
+    let first = state
+        .find_or_insert_merge_proposal_state(m)
+        .first_unprocessed_comment(m);
+    for idx in first..m.comments.len() {
+        if let Some(command) = Command::parse(&m.comments[idx].message_body) {
+            acknowledge_command(
+                m,
+                credentials,
+                state,
+                idx,
+                &command,
+                Acknowledgement::WaitingForCi,
+            )?;
+        }
+    }
     Ok(())
 }
 
+fn build_update_comment(target_branch: &launchpad::Branch) -> String {
+    format!(
+        "Merged lp:{} into this branch and pushed the result.",
+        target_branch.unique_name
+    )
+}
+
+fn build_fix_formatting_comment(changed_files: &[String]) -> String {
+    if changed_files.is_empty() {
+        return "Ran the formatting script: nothing to fix.".to_string();
+    }
+    let mut comment = String::new();
+    comment.push_str("Fixed formatting and pushed the result. Changed files:\n\n");
+    for path in changed_files {
+        comment.push_str(&format!("- {}\n", path));
+    }
+    comment
+}
+
+fn build_new_commits_comment(entries: &[&launchpad::LogEntry], complete: bool) -> String {
+    let mut comment = if complete {
+        "New revisions in the source branch:\n\n".to_string()
+    } else {
+        "The source branch changed. Latest revisions:\n\n".to_string()
+    };
+    for entry in entries {
+        comment.push_str(&format!(
+            "- r{} ({}): {}\n",
+            entry.revno, entry.author, entry.summary
+        ));
+    }
+    comment
+}
+
+fn build_mirror_comment(branch: &launchpad::Branch, sha: &str) -> String {
+    format!(
+        "Recreated the git mirror from scratch. Branch {} is now at {}.",
+        branch.slug, sha
+    )
+}
+
+fn build_error_report_comment(action: &str, err: &Error) -> String {
+    match *err.kind() {
+        ErrorKind::MergeConflicts(ref paths) => {
+            let mut comment = format!(
+                "Error {} this proposal. The merge produced conflicts in {} file(s):\n\n",
+                action,
+                paths.len()
+            );
+            for path in paths {
+                comment.push_str(&format!("- {}\n", path));
+            }
+            comment.push_str(
+                "\nNothing was committed. Please resolve the conflicts manually, for example by \
+                 merging the target branch into this branch.",
+            );
+            comment
+        }
+        ErrorKind::VerificationFailed(ref output) => {
+            let mut comment = format!(
+                "Error {} this proposal. The merged tree did not pass verification, so nothing \
+                 was committed. The last lines of output were:\n\n",
+                action
+            );
+            for line in output.tail(50).lines() {
+                comment.push_str(&format!("    {}\n", line));
+            }
+            comment
+        }
+        _ => format!("Error {} this proposal:\n\n{}", action, err),
+    }
+}
+
+fn build_conflicts_comment(m: &launchpad::MergeProposal, conflicts: &[String]) -> String {
+    let mut comment = format!(
+        "This proposal no longer merges cleanly into lp:{}. Conflicting files:\n\n",
+        m.target_branch.unique_name
+    );
+    for path in conflicts {
+        comment.push_str(&format!("- {}\n", path));
+    }
+    comment.push_str("\nUse @bunnybot update or merge the target branch manually to fix this.");
+    comment
+}
+
+fn build_conflicts_resolved_comment(m: &launchpad::MergeProposal) -> String {
+    format!(
+        "This proposal merges cleanly into lp:{} again.",
+        m.target_branch.unique_name
+    )
+}
+
+/// Test merges open proposals into trunk and comments when a proposal starts or stops
+/// conflicting. If trunk did not move since the last check, only proposals that conflicted
+/// before are checked again, to find out if they have been fixed.
+fn check_for_conflicts(
+    merge_proposals: &[launchpad::MergeProposal],
+    credentials: &Credentials,
+    state: &mut State,
+    bzr_repo: &Path,
+) -> Result<()> {
+    let trunk = launchpad::Branch::from_unique_name(TRUNK);
+    let trunk_revision_id = trunk.revision(bzr_repo)?.revision_id;
+    let trunk_moved = state.conflicts_checked_revision_id.as_ref() != Some(&trunk_revision_id);
+    // If we could not tell somebody about a change, all proposals are checked again next time.
+    let mut all_reported = true;
+
+    for m in merge_proposals {
+        let old_conflicts = state
+            .find_or_insert_merge_proposal_state(m)
+            .conflicts
+            .clone();
+        if !trunk_moved && old_conflicts.is_empty() {
+            continue;
+        }
+        println!(
+            "===> Checking {} -> {} for conflicts",
+            m.source_branch.unique_name, m.target_branch.unique_name
+        );
+        let conflicts = match m.target_branch.check_merge(bzr_repo, &m.source_branch) {
+            Ok(conflicts) => conflicts,
+            Err(err) => {
+                println!("Could not check for conflicts. Skipping. Err: {}", err);
+                continue;
+            }
+        };
+        let result = if old_conflicts.is_empty() && !conflicts.is_empty() {
+            m.add_comment(credentials, &build_conflicts_comment(m, &conflicts))
+        } else if !old_conflicts.is_empty() && conflicts.is_empty() {
+            m.add_comment(credentials, &build_conflicts_resolved_comment(m))
+        } else {
+            Ok(())
+        };
+        if let Err(err) = result {
+            println!("Could not report conflicts. Skipping. Err: {}", err);
+            all_reported = false;
+            continue;
+        }
+        state.find_or_insert_merge_proposal_state(m).conflicts = conflicts;
+    }
+    if all_reported {
+        state.conflicts_checked_revision_id = Some(trunk_revision_id);
+    }
+    Ok(())
+}
+
+fn build_revert_comment(record: &MergeRecord) -> String {
+    format!(
+        "Reverted revision {} (revid:{}) from lp:{} and updated the git mirror.",
+        record.revision.revno, record.revision.revision_id, record.target_branch
+    )
+}
+
+fn build_revert_not_mirrored_comment(record: &MergeRecord, err: &Error) -> String {
+    format!(
+        "Reverted revision {} (revid:{}) from lp:{}, but could not update the git mirror. This is \
+         retried on the next run. Error:\n\n{}",
+        record.revision.revno, record.revision.revision_id, record.target_branch, err
+    )
+}
+
+/// Looks for revert commands on recently merged proposals and backs out their merges.
+fn handle_revert_requests(
+    credentials: &Credentials,
+    state: &mut State,
+    bzr_repo: &Path,
+    git_repo: &Path,
+    data_dir: &Path,
+) -> Result<()> {
+    // Reading the comments of every proposal ever merged would be too slow. Nobody asks to revert
+    // old merges anyways.
+    const NUM_REVERTIBLE_MERGES: usize = 20;
+
+    let first = state
+        .merge_history
+        .len()
+        .saturating_sub(NUM_REVERTIBLE_MERGES);
+    for idx in first..state.merge_history.len() {
+        let record = state.merge_history[idx].clone();
+        if record.reverted {
+            continue;
+        }
+        let m = match launchpad::get_merge_proposal(&record.merge_proposal) {
+            Ok(m) => m,
+            Err(err) => {
+                println!(
+                    "Could not get {}. Skipping. Err: {}",
+                    record.merge_proposal, err
+                );
+                continue;
+            }
+        };
+        let first_comment = record.num_comments.min(m.comments.len());
+        let requested = m.comments[first_comment..]
+            .iter()
+            .any(|c| Command::parse(&c.message_body) == Some(Command::Revert));
+        state.merge_history[idx].num_comments = m.comments.len();
+        if !requested {
+            continue;
+        }
+
+        println!(
+            "===> Reverting {} from {}",
+            record.source_branch, record.target_branch
+        );
+        if let Err(err) = m.revert(bzr_repo, &record.revision) {
+            m.add_comment(credentials, &build_error_report_comment("reverting", &err))?;
+            state.save(data_dir)?;
+            continue;
+        }
+        // The revert is on Launchpad now. It must never be done twice, even if mirroring fails.
+        state.merge_history[idx].reverted = true;
+        state.save(data_dir)?;
+        let comment = match update_git_master(bzr_repo, git_repo, state) {
+            Ok(()) => build_revert_comment(&record),
+            Err(err) => build_revert_not_mirrored_comment(&record, &err),
+        };
+        m.add_comment(credentials, &comment)?;
+        state.save(data_dir)?;
+    }
+    Ok(())
+}
+
+/// Fast-forwards master to trunk and pushes it to GitHub. If somebody else pushed to master on
+/// GitHub, this fails instead of overwriting it.
+fn update_git_master(bzr_repo: &Path, git_repo: &Path, state: &mut State) -> Result<()> {
+    let trunk = launchpad::Branch::from_unique_name(TRUNK);
+    trunk.update(bzr_repo)?;
+    trunk.update_git(git_repo, &mut state.pushed_shas)?;
+
+    git::fast_forward(git_repo, "master", &trunk.slug)?;
+    let lease = state.pushed_shas.get("master").map(|s| s.as_str());
+    let sha = git::push_with_lease(git_repo, "github", "master", lease)?;
+    state.pushed_shas.insert("master".to_string(), sha);
+    Ok(())
+}
+
+/// Records which git commits the mainline revisions of 'branch' were mirrored to. Only revisions
+/// since the last call are looked at, unless the history of the branch was overwritten.
+fn record_revisions(
+    branch: &launchpad::Branch,
+    bzr_repo: &Path,
+    git_repo: &Path,
+    revision_map: &mut RevisionMap,
+) -> Result<()> {
+    if !branch.is_branched(bzr_repo) || !git::branches(git_repo)?.contains(&branch.slug) {
+        return Ok(());
+    }
+    let tip = branch.revision(bzr_repo)?;
+    let name = &branch.unique_name;
+    if revision_map.revision_id(name, tip.revno) == Some(tip.revision_id.as_str()) {
+        return Ok(());
+    }
+    let history = git::first_parent_history(git_repo, &branch.slug)?;
+    if history.len() != tip.revno as usize {
+        bail!(
+            "The git mirror of {} has {} commits, but bzr has {} revisions.",
+            branch.slug,
+            history.len(),
+            tip.revno
+        );
+    }
+
+    // We also fetch the last recorded revision, to check that it is still the same.
+    let last_revno = revision_map.last_revno(name).unwrap_or(0).min(tip.revno);
+    let mut log = branch.log(bzr_repo, (tip.revno - last_revno + 1) as usize)?;
+    let unchanged = match log.last() {
+        Some(oldest) => {
+            revision_map.revision_id(name, oldest.revno) == Some(oldest.revision_id.as_str())
+        }
+        None => false,
+    };
+    // Without recorded revisions, the log is complete already.
+    if last_revno > 0 && !unchanged {
+        revision_map.forget_revnos(name);
+        log = branch.log(bzr_repo, tip.revno as usize)?;
+    }
+    for entry in log {
+        let sha = &history[entry.revno as usize - 1];
+        revision_map.record(name, entry.revno, &entry.revision_id, sha);
+    }
+    Ok(())
+}
+
+/// Creates annotated git tags for the bzr tags on the mainline of 'branch' and pushes the ones
+/// that are missing on GitHub. The branch must be mirrored to git already.
+fn mirror_tags(
+    branch: &launchpad::Branch,
+    config: &Config,
+    bzr_repo: &Path,
+    git_repo: &Path,
+) -> Result<()> {
+    let history = git::first_parent_history(git_repo, &branch.slug)?;
+    // Revnos only map to commits if the mirror has exactly the bzr mainline. A tag on the wrong
+    // commit would stay on GitHub for good.
+    let revno = branch.revision(bzr_repo)?.revno;
+    if history.len() != revno as usize {
+        bail!(
+            "The git mirror of {} has {} commits, but bzr has {} revisions. Not mirroring tags.",
+            branch.slug,
+            history.len(),
+            revno
+        );
+    }
+    // GitHub decides what is missing, so that tags created during a failed run are pushed later.
+    let pushed = git::remote_refs(git_repo, "github", "refs/tags/")?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let mut missing = Vec::new();
+    for tag in branch.tags(bzr_repo)? {
+        let name = format!("{}{}", config.tag_prefix, tag.name);
+        if !git::is_valid_tag_name(&name) || pushed.contains(&format!("refs/tags/{}", name)) {
+            continue;
+        }
+        if git::has_tag(git_repo, &name)? {
+            missing.push(name);
+            continue;
+        }
+        // Revno N is the Nth commit of the first-parent history.
+        let index = tag.revno.and_then(|revno| (revno as usize).checked_sub(1));
+        let sha = match index.and_then(|index| history.get(index)) {
+            Some(sha) => sha,
+            None => {
+                println!(
+                    "Tag {} is not on the mainline of {}.",
+                    tag.name, branch.slug
+                );
+                continue;
+            }
+        };
+        let message = format!("Tag {} of lp:{}.", tag.name, branch.unique_name);
+        git::create_tag(git_repo, &name, sha, &message)?;
+        missing.push(name);
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    println!("Pushing new tags: {}", missing.join(", "));
+    let refspecs = missing
+        .iter()
+        .map(|name| format!("refs/tags/{0}:refs/tags/{0}", name))
+        .collect::<Vec<_>>();
+    let refspecs = refspecs.iter().map(|r| r.as_str()).collect::<Vec<_>>();
+    git::push(git_repo, "github", &refspecs)
+}
+
 #[cfg(target_os = "linux")]
 fn set_nice_level() {
     scheduler::set_self_priority(scheduler::Which::Process, 10).unwrap();
@@ -192,35 +1075,87 @@ fn set_nice_level() {
 #[cfg(not(target_os = "linux"))]
 fn set_nice_level() {}
 
+/// Comments with the revisions that were added to the source branch since 'last_tip'.
+fn post_new_commits(
+    m: &launchpad::MergeProposal,
+    credentials: &Credentials,
+    bzr_repo: &Path,
+    last_tip: &str,
+) -> Result<()> {
+    const MAX_ENTRIES: usize = 20;
+    let log = m.source_branch.log(bzr_repo, MAX_ENTRIES + 1)?;
+    let new_entries = log
+        .iter()
+        .take_while(|e| e.revision_id != last_tip)
+        .take(MAX_ENTRIES)
+        .collect::<Vec<_>>();
+    // If we did not find the last tip, history was rewritten or there are too many new revisions.
+    let complete = new_entries.len() < log.len();
+    if new_entries.is_empty() {
+        return Ok(());
+    }
+    m.add_comment(
+        credentials,
+        &build_new_commits_comment(&new_entries, complete),
+    )
+}
+
 fn handle_merge_proposal(
     m: &launchpad::MergeProposal,
     credentials: &Credentials,
     state: &mut State,
     bzr_repo: &Path,
     git_repo: &Path,
+    config: &Config,
     always_update: bool,
 ) -> Result<()> {
-    let was_updated = m.source_branch.update(&bzr_repo)?;
-    if always_update || was_updated {
-        m.source_branch.update_git(&git_repo)?;
+    let last_tip = state
+        .branches
+        .get(&m.source_branch.unique_name)
+        .and_then(|b| b.revision_id.clone());
+    let was_pulled = m.source_branch.update(&bzr_repo)?;
+    let tip = m.source_branch.revision(bzr_repo)?.revision_id;
+    let was_updated = {
+        let branch_state = state
+            .branches
+            .entry(m.source_branch.unique_name.clone())
+            .or_default();
+        // The tip might also differ if we pulled during a run that failed before mirroring.
+        let was_updated = was_pulled || branch_state.revision_id.as_ref() != Some(&tip);
+        if always_update || was_updated {
+            m.source_branch
+                .update_git(git_repo, &mut state.pushed_shas)?;
+            branch_state.revision_id = Some(tip);
+        }
+        was_updated
+    };
+
+    if was_updated
+        && config
+            .new_commits_summary
+            .contains(&m.target_branch.unique_name)
+    {
+        if let Some(last_tip) = last_tip {
+            post_new_commits(m, credentials, bzr_repo, &last_tip)?;
+        }
     }
 
     // If we were updated, there is no point in checking/updating CI state: It will rerun very
     // soon again anyways.
     if was_updated {
-        return Ok(());
+        return acknowledge_waiting_commands(m, credentials, state);
     }
 
     // Getting the appveyor state is often the slowest part in handling a branch.
     let travis_state = m.source_branch.travis_state()?;
     if travis_state.is_transitional() {
         println!("Travis state is transitional: {}", travis_state.state);
-        return Ok(());
+        return acknowledge_waiting_commands(m, credentials, state);
     }
     let appveyor_state = m.source_branch.appveyor_state()?;
     if appveyor_state.is_transitional() {
         println!("Appveyor state is transitional: {}", appveyor_state.state);
-        return Ok(());
+        return acknowledge_waiting_commands(m, credentials, state);
     }
 
     // Update branch state.
@@ -241,41 +1176,521 @@ fn handle_merge_proposal(
 
         branch_state.travis_state = travis_state.clone();
         branch_state.appveyor_state = appveyor_state;
+        branch_state.ci_revision_id = branch_state.revision_id.clone();
     }
 
     // Update merge proposal state.
     {
         let merge_proposal_state = state.find_or_insert_merge_proposal_state(&m);
-        let old_num_comments = if merge_proposal_state.num_comments <= m.comments.len() {
-            merge_proposal_state.num_comments
-        } else {
-            println!("Number of comment s decreased. Probably a reopened PR. Forgetting state.");
-            0
-        };
+        let old_num_comments = merge_proposal_state.first_unprocessed_comment(m);
         merge_proposal_state.num_comments = m.comments.len();
-        for comment in &m.comments[old_num_comments..] {
+        let new_comments = &m.comments[old_num_comments..];
+        for (idx, comment) in new_comments.iter().enumerate() {
+            let command = match Command::parse(&comment.message_body) {
+                Some(command) => command,
+                None => continue,
+            };
+            let comment_index = old_num_comments + idx;
+            let refused = match command {
+                Command::Merge { force, .. } => {
+                    !force && !failing_merge_checks(&travis_state).is_empty()
+                }
+                _ => false,
+            };
+            if refused {
+                // The refusal is the acknowledgement.
+                m.add_comment(credentials, &build_refuse_merge_comment(&travis_state))?;
+                mark_command_acknowledged(m, state, comment_index);
+                continue;
+            }
+            if let Command::Merge {
+                force,
+                priority,
+                ref commit_message,
+            } = command
+            {
+                // Merges are executed in order after all proposals have been looked at.
+                if !state
+                    .merge_queue
+                    .iter()
+                    .any(|e| e.comment == comment.self_link)
+                {
+                    state.merge_queue.push(MergeQueueEntry {
+                        source_branch: m.source_branch.unique_name.clone(),
+                        target_branch: m.target_branch.unique_name.clone(),
+                        comment: comment.self_link.clone(),
+                        comment_number: comment_index + 1,
+                        requested_at: comment.date_created.clone(),
+                        priority,
+                        force,
+                        commit_message: commit_message.clone(),
+                        revision_id: state
+                            .branches
+                            .get(&m.source_branch.unique_name)
+                            .and_then(|b| b.revision_id.clone()),
+                        outcome: None,
+                    });
+                }
+                acknowledge_command(
+                    m,
+                    credentials,
+                    state,
+                    comment_index,
+                    &command,
+                    Acknowledgement::Queued,
+                )?;
+                continue;
+            }
+            if command == Command::Status {
+                // The status report is the acknowledgement.
+                mark_command_acknowledged(m, state, comment_index);
+            } else {
+                acknowledge_command(
+                    m,
+                    credentials,
+                    state,
+                    comment_index,
+                    &command,
+                    Acknowledgement::Executing,
+                )?;
+            }
+
+            let action;
             let result;
-            if MERGE_FORCE_REGEX.find(&comment.message_body).is_some() {
-                result = m.merge(bzr_repo);
-            } else if MERGE_REGEX.find(&comment.message_body).is_some() {
-                if travis_state.state != "passed" {
-                    m.add_comment(credentials, &build_refuse_merge_comment(&travis_state))?;
-                    result = Ok(());
-                } else {
-                    result = m.merge(bzr_repo);
+            match command {
+                Command::Merge { .. } => unreachable!(),
+                Command::Update => {
+                    action = "updating";
+                    result = m
+                        .update_source(bzr_repo, git_repo, &mut state.pushed_shas)
+                        .and_then(|_| {
+                            m.add_comment(credentials, &build_update_comment(&m.target_branch))
+                        });
+                }
+                Command::FixFormatting => {
+                    action = "fixing formatting of";
+                    result = m
+                        .fix_formatting(bzr_repo, git_repo, &mut state.pushed_shas)
+                        .and_then(|changed_files| {
+                            m.add_comment(
+                                credentials,
+                                &build_fix_formatting_comment(&changed_files),
+                            )
+                        });
                 }
+                Command::Revert => {
+                    action = "reverting";
+                    result = Err("Only merged proposals can be reverted.".into());
+                }
+                Command::Mirror => {
+                    action = "mirroring";
+                    result = m
+                        .source_branch
+                        .resync(bzr_repo, git_repo, &mut state.pushed_shas)
+                        .and_then(|sha| {
+                            m.add_comment(
+                                credentials,
+                                &build_mirror_comment(&m.source_branch, &sha),
+                            )
+                        });
+                }
+                Command::Status => {
+                    // Status only reports, so later commands in the same run still get executed.
+                    let pending_commands = new_comments[idx + 1..]
+                        .iter()
+                        .filter_map(|c| Command::parse(&c.message_body))
+                        .collect::<Vec<_>>();
+                    m.add_comment(
+                        credentials,
+                        &build_status_comment(m, state, git_repo, &pending_commands),
+                    )?;
+                    continue;
+                }
+            }
+            match result {
+                Ok(()) => state.find_or_insert_merge_proposal_state(m).last_failure = None,
+                Err(err) => {
+                    m.add_comment(credentials, &build_error_report_comment(action, &err))?;
+                    state.find_or_insert_merge_proposal_state(m).last_failure =
+                        Some(Failure::new(action, &err));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn record_merge(
+    state: &mut State,
+    idx: usize,
+    m: &launchpad::MergeProposal,
+    revision: launchpad::Revision,
+) {
+    let entry = &mut state.merge_queue[idx];
+    entry.outcome = Some(MergeOutcome::Merged);
+    state.merge_history.push(MergeRecord {
+        source_branch: entry.source_branch.clone(),
+        target_branch: entry.target_branch.clone(),
+        merge_proposal: m.self_link().to_string(),
+        num_comments: m.comments.len(),
+        revision,
+        merged_at: Utc::now().to_rfc3339(),
+        reverted: false,
+    });
+    state.find_or_insert_merge_proposal_state(m).last_failure = None;
+}
+
+fn record_merge_failure(
+    state: &mut State,
+    idx: usize,
+    m: &launchpad::MergeProposal,
+    credentials: &Credentials,
+    err: &Error,
+) -> Result<()> {
+    m.add_comment(credentials, &build_error_report_comment("merging", err))?;
+    state.find_or_insert_merge_proposal_state(m).last_failure = Some(Failure::new("merging", err));
+    state.merge_queue[idx].outcome = Some(MergeOutcome::Failed(err.to_string()));
+    Ok(())
+}
+
+/// Merges all proposals in 'batch' into a staging copy of their common target, verifies the
+/// result once and pushes it. If verification fails, the batch is split in half and both halves
+/// are tried separately, until the proposals that break verification are found.
+fn merge_batch(
+    batch: &[(usize, &launchpad::MergeProposal)],
+    config: &Config,
+    credentials: &Credentials,
+    state: &mut State,
+    bzr_repo: &Path,
+    data_dir: &Path,
+) -> Result<()> {
+    let target = &batch[0].1.target_branch;
+    println!(
+        "===> Merging a batch of {} into {}",
+        batch.len(),
+        target.unique_name
+    );
+    target.update(bzr_repo)?;
+    let staging = target.create_staging(bzr_repo)?;
+
+    let mut merged = Vec::new();
+    for &(idx, m) in batch {
+        let commit_message = state.merge_queue[idx].commit_message.clone();
+        match m.merge_into_staging(bzr_repo, &staging, commit_message.as_deref()) {
+            Ok(revision) => merged.push((idx, m, revision)),
+            Err(err) => record_merge_failure(state, idx, m, credentials, &err)?,
+        }
+    }
+
+    let verified = match (merged.is_empty(), &config.verification) {
+        (true, _) | (false, None) => Ok(()),
+        (false, Some(verification)) => staging.verify(bzr_repo, verification),
+    };
+    match verified {
+        Ok(()) => {
+            if !merged.is_empty() {
+                staging.publish_staging(bzr_repo)?;
+                target.update(bzr_repo)?;
+            }
+            staging.discard_staging(bzr_repo)?;
+            for (idx, m, revision) in merged {
+                record_merge(state, idx, m, revision);
+            }
+        }
+        Err(err) => {
+            staging.discard_staging(bzr_repo)?;
+            if merged.len() == 1 {
+                let (idx, m, _) = merged[0];
+                record_merge_failure(state, idx, m, credentials, &err)?;
             } else {
+                println!("Verification of the batch failed. Bisecting.");
+                let entries = merged
+                    .iter()
+                    .map(|&(idx, m, _)| (idx, m))
+                    .collect::<Vec<_>>();
+                let (first, second) = entries.split_at(entries.len() / 2);
+                merge_batch(first, config, credentials, state, bzr_repo, data_dir)?;
+                merge_batch(second, config, credentials, state, bzr_repo, data_dir)?;
+            }
+        }
+    }
+    state.save(data_dir)?;
+    Ok(())
+}
+
+/// Handled merge queue entries are forgotten after this many days.
+const MERGE_QUEUE_MAX_AGE_DAYS: i64 = 30;
+
+/// Merges the queued proposals one after the other, or in batches if configured. Every merge
+/// starts from the latest target, so each proposal is validated on top of everything that was
+/// merged before it.
+fn process_merge_queue(
+    merge_proposals: &[launchpad::MergeProposal],
+    config: &Config,
+    credentials: &Credentials,
+    state: &mut State,
+    bzr_repo: &Path,
+    data_dir: &Path,
+) -> Result<()> {
+    state.prune_merge_queue(MERGE_QUEUE_MAX_AGE_DAYS);
+    let mut ready = Vec::new();
+    for idx in state.pending_merges() {
+        let entry = state.merge_queue[idx].clone();
+        let m = match merge_proposals.iter().find(|m| {
+            m.source_branch.unique_name == entry.source_branch
+                && m.target_branch.unique_name == entry.target_branch
+        }) {
+            Some(m) => m,
+            None => {
+                state.merge_queue[idx].outcome = Some(MergeOutcome::Failed(
+                    "The proposal is no longer open.".to_string(),
+                ));
+                continue;
+            }
+        };
+
+        // We merge what is in the local branch, which might be newer than what CI saw.
+        if !m.source_branch.is_branched(bzr_repo) {
+            println!(
+                "Not merging {} yet: it is not branched.",
+                entry.source_branch
+            );
+            continue;
+        }
+        let tip = match m.source_branch.revision(bzr_repo) {
+            Ok(revision) => revision.revision_id,
+            Err(err) => {
+                println!("Not merging {} yet: {}", entry.source_branch, err);
                 continue;
             }
-            if let Err(err) = result {
-                m.add_comment(credentials, &build_error_report_comment(&err))?;
+        };
+        if entry.revision_id.as_ref() != Some(&tip) {
+            m.add_comment(credentials, &build_branch_changed_comment(m))?;
+            state.merge_queue[idx].outcome = Some(MergeOutcome::Refused(
+                "The branch changed since the merge was requested.".to_string(),
+            ));
+            state.save(data_dir)?;
+            continue;
+        }
+        let branch_state = state
+            .branches
+            .get(&entry.source_branch)
+            .cloned()
+            .unwrap_or_default();
+        if !entry.force && branch_state.ci_revision_id.as_ref() != Some(&tip) {
+            // CI results are not recorded for this revision yet.
+            continue;
+        }
+        let travis_state = branch_state.travis_state;
+        if !entry.force && !failing_merge_checks(&travis_state).is_empty() {
+            m.add_comment(credentials, &build_refuse_merge_comment(&travis_state))?;
+            state.merge_queue[idx].outcome = Some(MergeOutcome::Refused(travis_state.state));
+            state.save(data_dir)?;
+            continue;
+        }
+        ready.push((idx, m));
+    }
+
+    if config.batch_merges && ready.len() > 1 {
+        let mut targets = Vec::new();
+        for &(_, m) in &ready {
+            if !targets.contains(&&m.target_branch.unique_name) {
+                targets.push(&m.target_branch.unique_name);
+            }
+        }
+        for target in targets {
+            let batch = ready
+                .iter()
+                .filter(|&&(_, m)| &m.target_branch.unique_name == target)
+                .cloned()
+                .collect::<Vec<_>>();
+            merge_batch(&batch, config, credentials, state, bzr_repo, data_dir)?;
+        }
+        return Ok(());
+    }
+
+    for (idx, m) in ready {
+        println!(
+            "===> Merging {} -> {}",
+            m.source_branch.unique_name, m.target_branch.unique_name
+        );
+        let commit_message = state.merge_queue[idx].commit_message.clone();
+        match m.merge(
+            bzr_repo,
+            commit_message.as_deref(),
+            config.verification.as_ref(),
+        ) {
+            Ok(revision) => record_merge(state, idx, m, revision),
+            Err(err) => record_merge_failure(state, idx, m, credentials, &err)?,
+        }
+        state.save(data_dir)?;
+    }
+    Ok(())
+}
+
+/// Version of 'launchpad::slugify'. Version 0 is 'launchpad::legacy_slugify'.
+const SLUG_VERSION: u32 = 1;
+
+/// Renames the bzr branches of an old data dir to their current slugs. Branches that shared a
+/// legacy slug are deleted, since we cannot tell whom they belong to. The git branches are
+/// deleted locally and on GitHub and get recreated under their new names by the next mirroring.
+fn migrate_slugs(state: &mut State, bzr_repo: &Path, git_repo: &Path) -> Result<()> {
+    println!("===> Migrating slugs to version {}", SLUG_VERSION);
+    let mut names = state.branches.keys().cloned().collect::<HashSet<_>>();
+    for proposal in &state.merge_proposals {
+        names.insert(proposal.source_branch.clone());
+        names.insert(proposal.target_branch.clone());
+    }
+    names.insert(TRUNK.to_string());
+    let mut by_legacy_slug = HashMap::<String, Vec<String>>::new();
+    for name in names {
+        by_legacy_slug
+            .entry(launchpad::legacy_slugify(&name))
+            .or_default()
+            .push(name);
+    }
+
+    let git_branches = git::branches(git_repo)?;
+    for (legacy_slug, names) in by_legacy_slug {
+        let legacy_path = bzr_repo.join(&legacy_slug);
+        if names.len() > 1 {
+            println!(
+                "{} is shared by {}. Deleting it, so that every branch gets its own.",
+                legacy_slug,
+                names.join(", ")
+            );
+            if legacy_path.exists() {
+                fs::remove_dir_all(&legacy_path)
+                    .chain_err(|| format!("Could not delete {}.", legacy_slug))?;
+            }
+        } else {
+            let path = bzr_repo.join(launchpad::slugify(&names[0]));
+            if legacy_path.exists() && !path.exists() {
+                fs::rename(&legacy_path, &path)
+                    .chain_err(|| format!("Could not rename {}.", legacy_slug))?;
             }
-            break;
         }
+
+        if git_branches.contains(&legacy_slug) {
+            git::delete_local_branch(git_repo, &legacy_slug)?;
+        }
+        let _ = git::delete_ref(
+            git_repo,
+            &format!("refs/remotes/bzr_origin/{}", legacy_slug),
+        );
+        let _ = git::delete_remote_branch(git_repo, &legacy_slug)
+            .map_err(|err| println!("Ignored error while deleting remote branch: {}", err));
+        state.pushed_shas.remove(&legacy_slug);
     }
+
+    // Forgetting the mirrored tips makes sure every branch gets mirrored again.
+    for branch_state in state.branches.values_mut() {
+        branch_state.revision_id = None;
+    }
+    state.slug_version = SLUG_VERSION;
     Ok(())
 }
 
+/// Reports disk usage, removes branch directories we have no state for and compacts the
+/// repositories. Protected branches are kept and the dry run and limit settings for deleting
+/// branches apply to the directories as well.
+fn run_maintenance(
+    config: &Config,
+    state: &mut State,
+    bzr_repo: &Path,
+    git_repo: &Path,
+) -> Result<()> {
+    println!("===> Maintenance");
+    println!("Disk usage in kB:");
+    for (slug, kb) in maintenance::branch_disk_usage(bzr_repo)? {
+        println!("{:>10}  {}", kb, slug);
+    }
+    println!("{:>10}  git_repo", maintenance::disk_usage(git_repo)?);
+
+    let mut slugs = state.slugs();
+    slugs.extend(protected_slugs(config));
+    let orphans = maintenance::orphaned_dirs(bzr_repo, &slugs)?;
+    let deletion = &config.deletion;
+    if orphans.len() > deletion.max_per_run {
+        bail!(
+            "Refusing to delete {} orphaned directories at once, the limit is {}: {}",
+            orphans.len(),
+            deletion.max_per_run,
+            orphans.join(", ")
+        );
+    }
+    for slug in &orphans {
+        if deletion.dry_run {
+            println!("Would delete orphaned directory {}.", slug);
+            continue;
+        }
+        fs::remove_dir_all(bzr_repo.join(slug))
+            .chain_err(|| format!("Could not delete orphaned directory {}.", slug))?;
+        println!("Deleted orphaned directory {}.", slug);
+    }
+
+    maintenance::compact(bzr_repo, git_repo)?;
+    println!(
+        "Free space after maintenance: {} kB",
+        maintenance::free_space(bzr_repo)?
+    );
+    state.last_maintenance = Some(Utc::now().to_rfc3339());
+    Ok(())
+}
+
+/// Returns true if the periodic maintenance is due.
+fn maintenance_due(config: &Config, state: &State) -> bool {
+    let interval_hours = match config.maintenance.interval_hours {
+        Some(hours) => hours as i64,
+        None => return false,
+    };
+    match state
+        .last_maintenance
+        .as_ref()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+    {
+        Some(last) => {
+            Utc::now().signed_duration_since(last) > chrono::Duration::hours(interval_hours)
+        }
+        None => true,
+    }
+}
+
+/// Returns false if branching a new proposal would leave less free space than configured.
+fn has_space_for_new_branch(config: &Config, bzr_repo: &Path) -> Result<bool> {
+    match config.maintenance.min_free_space_mb {
+        Some(min_mb) => Ok(maintenance::free_space(bzr_repo)? >= min_mb * 1024),
+        None => Ok(true),
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum Subcommand {
+    /// Report disk usage, delete orphaned branches and compact the repositories, then exit.
+    Maintenance,
+    /// Manage the branches that were archived on GitHub when they were deleted.
+    Archive {
+        #[structopt(subcommand)]
+        command: ArchiveCommand,
+    },
+    /// Translate between bzr revnos or revision ids and git SHAs of the mirror.
+    Lookup {
+        /// A revno of the branch, a bzr revision id or a (possibly abbreviated) git SHA.
+        query: String,
+        /// The branch that revnos refer to.
+        #[structopt(long, default_value = "~widelands-dev/widelands/trunk")]
+        branch: String,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum ArchiveCommand {
+    /// List the archived branches with their age.
+    List,
+    /// Delete archived branches that are older than the given number of days.
+    Prune { days: i64 },
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "bunnybot", about = "Mergebot for the Widelands project")]
 struct Arguments {
@@ -285,24 +1700,77 @@ struct Arguments {
 
     /// Update git branches, even if it seems bzr has not changed.
     always_update: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Subcommand>,
+}
+
+fn lookup(data_dir: &Path, branch: &str, query: &str) -> Result<()> {
+    let mappings = RevisionMap::load(data_dir)?.lookup(branch, query);
+    if mappings.is_empty() {
+        bail!("No revision found for {}.", query);
+    }
+    for mapping in mappings {
+        let revno = match mapping.revno {
+            Some(revno) => format!("r{}", revno),
+            None => "-".to_string(),
+        };
+        println!("{} {} {}", revno, mapping.revision_id, mapping.sha);
+    }
+    Ok(())
 }
 
 fn run() -> Result<()> {
+    let args = Arguments::from_args();
+    // Lookups only read, so they do not need to wait for a running bunnybot.
+    if let Some(Subcommand::Lookup { query, branch }) = &args.command {
+        return lookup(&args.data_dir, branch, query);
+    }
+
     let _pidfile = Pidfile::new()?;
     set_nice_level();
 
-    let args = Arguments::from_args();
     let bzr_repo = args.data_dir.join(Path::new("bzr_repo"));
     let git_repo = args.data_dir.join(Path::new("git_repo"));
 
-    let credentials = Credentials::load(&args.data_dir)?;
+    let config = Config::load(&args.data_dir)?;
+    let timeouts = &config.timeouts;
+    subprocess::set_default_timeout("bzr", Duration::from_secs(timeouts.bzr_secs));
+    subprocess::set_default_timeout("git", Duration::from_secs(timeouts.git_secs));
+    subprocess::set_default_timeout(
+        launchpad::FORMATTER,
+        Duration::from_secs(timeouts.formatter_secs),
+    );
     let mut state = State::load(&args.data_dir)?;
+    if state.slug_version < SLUG_VERSION {
+        migrate_slugs(&mut state, &bzr_repo, &git_repo)?;
+        state.save(&args.data_dir)?;
+    }
+    match args.command {
+        Some(Subcommand::Maintenance) => {
+            run_maintenance(&config, &mut state, &bzr_repo, &git_repo)?;
+            state.save(&args.data_dir)?;
+            return Ok(());
+        }
+        Some(Subcommand::Archive { command }) => {
+            let prune_days = match command {
+                ArchiveCommand::List => None,
+                ArchiveCommand::Prune { days } => Some(days),
+            };
+            return manage_archive(&git_repo, prune_days);
+        }
+        Some(Subcommand::Lookup { .. }) | None => (),
+    }
+    let mut revision_map = RevisionMap::load(&args.data_dir)?;
+    let credentials = Credentials::load(&args.data_dir)?;
 
     let mut branches_slug = HashSet::<String>::new();
 
-    let merge_proposals =
-        bunnybot::launchpad::get_merge_proposals("~widelands-dev/widelands/trunk")?;
-    for m in merge_proposals {
+    let mut merge_proposals = bunnybot::launchpad::get_merge_proposals(TRUNK)?;
+    for repository in &config.git_repositories {
+        merge_proposals.extend(launchpad::get_merge_proposals(repository)?);
+    }
+    for m in &merge_proposals {
         println!(
             "===> Working on {} -> {}",
             m.source_branch.unique_name, m.target_branch.unique_name
@@ -310,30 +1778,188 @@ fn run() -> Result<()> {
         branches_slug.insert(m.target_branch.slug.clone());
         branches_slug.insert(m.source_branch.slug.clone());
 
+        if !m.source_branch.is_branched(&bzr_repo) && !has_space_for_new_branch(&config, &bzr_repo)?
+        {
+            println!("Not enough free space to branch a new proposal. Skipping.");
+            continue;
+        }
+
         let before_state = state.clone();
         if let Err(err) = handle_merge_proposal(
-            &m,
+            m,
             &credentials,
             &mut state,
             &bzr_repo,
             &git_repo,
+            &config,
             args.always_update,
         ) {
             println!("Unhandled error with this proposal. Skipping. Err: {}", err);
+            // Acknowledgements have been posted already, so they and the queued merges they
+            // announced must survive the rollback. So must the SHAs of pushes that happened.
+            let acknowledged_commands = state
+                .find_or_insert_merge_proposal_state(m)
+                .acknowledged_commands
+                .clone();
+            let merge_queue = state.merge_queue.clone();
+            let pushed_shas = state.pushed_shas.clone();
             state = before_state;
+            state.merge_queue = merge_queue;
+            state.pushed_shas = pushed_shas;
+            let merge_proposal_state = state.find_or_insert_merge_proposal_state(m);
+            merge_proposal_state.last_failure = Some(Failure::new("handling the proposal", &err));
+            merge_proposal_state.acknowledged_commands = acknowledged_commands;
+            state.save(&args.data_dir).unwrap();
             continue;
         }
 
+        if let Err(err) =
+            record_revisions(&m.source_branch, &bzr_repo, &git_repo, &mut revision_map)
+        {
+            println!("Could not record revisions: {}", err);
+        }
+
         state.save(&args.data_dir).unwrap();
         println!("\n");
     }
     state.save(&args.data_dir).unwrap();
 
-    update_git_master(&bzr_repo, &git_repo)?;
-    delete_unmentioned_branches(&branches_slug, &mut state, &bzr_repo, &git_repo)?;
+    process_merge_queue(
+        &merge_proposals,
+        &config,
+        &credentials,
+        &mut state,
+        &bzr_repo,
+        &args.data_dir,
+    )?;
+    state.save(&args.data_dir).unwrap();
+
+    handle_revert_requests(
+        &credentials,
+        &mut state,
+        &bzr_repo,
+        &git_repo,
+        &args.data_dir,
+    )?;
+    state.save(&args.data_dir).unwrap();
+
+    update_git_master(&bzr_repo, &git_repo, &mut state)?;
+    if let Err(err) = record_revisions(
+        &launchpad::Branch::from_unique_name(TRUNK),
+        &bzr_repo,
+        &git_repo,
+        &mut revision_map,
+    ) {
+        println!("Could not record revisions: {}", err);
+    }
+    revision_map.save(&args.data_dir)?;
+    let trunk = launchpad::Branch::from_unique_name(TRUNK);
+    let mut targets = vec![&trunk];
+    for m in &merge_proposals {
+        // Targets in git repositories need their own Vcs, so we take the branch as it is.
+        if targets
+            .iter()
+            .all(|t| t.unique_name != m.target_branch.unique_name)
+        {
+            targets.push(&m.target_branch);
+        }
+    }
+    let mirrored = git::branches(&git_repo)?;
+    for target in targets {
+        if target.is_branched(&bzr_repo) && mirrored.contains(&target.slug) {
+            if let Err(err) = mirror_tags(target, &config, &bzr_repo, &git_repo) {
+                println!("Could not mirror tags: {}", err);
+            }
+        }
+    }
+    check_for_conflicts(&merge_proposals, &credentials, &mut state, &bzr_repo)?;
+    state.save(&args.data_dir).unwrap();
+    delete_unmentioned_branches(&branches_slug, &config, &mut state, &bzr_repo, &git_repo)?;
     state.save(&args.data_dir).unwrap();
 
+    if maintenance_due(&config, &state) {
+        run_maintenance(&config, &mut state, &bzr_repo, &git_repo)?;
+        state.save(&args.data_dir).unwrap();
+    }
+
     Ok(())
 }
 
 quick_main!(run);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(commit_message: Option<&str>) -> Option<Command> {
+        Some(Command::Merge {
+            force: false,
+            priority: 0,
+            commit_message: commit_message.map(|m| m.to_string()),
+        })
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("@bunnybot update"), Some(Command::Update));
+        assert_eq!(
+            Command::parse("Thanks!\n@bunnybot fix formatting"),
+            Some(Command::FixFormatting)
+        );
+        assert_eq!(Command::parse("bunnybot merge"), None);
+        assert_eq!(
+            Command::parse("@bunnybot status update please"),
+            Some(Command::Status)
+        );
+        assert_eq!(Command::parse("@bunnybot what's the merge status?"), None);
+        assert_eq!(
+            Command::parse("@bunnybot revert the merge"),
+            Some(Command::Revert)
+        );
+        assert_eq!(Command::parse("@bunnybot merged, thanks"), None);
+        assert_eq!(Command::parse("@bunnybot  mirror"), Some(Command::Mirror));
+        assert_eq!(
+            Command::parse("@bunnybot merge force priority 5"),
+            Some(Command::Merge {
+                force: true,
+                priority: 5,
+                commit_message: None,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_merge_without_commit_message() {
+        assert_eq!(Command::parse("@bunnybot merge"), merge(None));
+        assert_eq!(Command::parse("@bunnybot merge\n\n"), merge(None));
+        assert_eq!(
+            Command::parse("@bunnybot merge\nThanks for the work!"),
+            merge(None)
+        );
+    }
+
+    #[test]
+    fn parse_merge_with_commit_message() {
+        assert_eq!(
+            Command::parse("@bunnybot merge\n```\nFix the crash.\n\nDetails.\n```"),
+            merge(Some("Fix the crash.\n\nDetails."))
+        );
+        assert_eq!(
+            Command::parse("@bunnybot merge\n\n```Fix the crash.```\nCheers"),
+            merge(Some("Fix the crash."))
+        );
+    }
+
+    #[test]
+    fn fenced_block_must_follow_the_command() {
+        let body = "Looks good.\n@bunnybot merge\nCheers\n```\nNot a message.\n```";
+        assert_eq!(
+            fenced_block_after_line(body, body.find("merge").unwrap()),
+            None
+        );
+        // Unclosed and empty fences are ignored.
+        assert_eq!(fenced_block_after_line("merge\n```\nFix", 0), None);
+        assert_eq!(fenced_block_after_line("merge\n```\n\n```", 0), None);
+        assert_eq!(fenced_block_after_line("merge", 0), None);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::errors::*;
+use std::fs;
+use std::path::Path;
+
+/// Settings from config.json in the data directory. The file and every setting in it are
+/// optional.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Check that a merged tree must pass before it is committed and pushed.
+    pub verification: Option<Verification>,
+    /// Merge all queued proposals together and push them in one go, instead of one by one.
+    pub batch_merges: bool,
+    /// Unique names of target branches. When the source branch of a proposal into one of these
+    /// changes, a comment lists the new revisions.
+    pub new_commits_summary: Vec<String>,
+    pub maintenance: Maintenance,
+    /// Prepended to the names of bzr tags when they are mirrored to git, e.g. "bzr-".
+    pub tag_prefix: String,
+    pub deletion: Deletion,
+    pub timeouts: Timeouts,
+    /// Unique names of Launchpad git repositories, e.g. "~widelands-dev/widelands/+git/widelands".
+    /// Proposals into their branches are handled like those into trunk.
+    pub git_repositories: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct Maintenance {
+    /// Run maintenance at the end of a run if the last one is longer ago than this.
+    pub interval_hours: Option<u64>,
+    /// Do not branch new proposals if less space than this is available for the data directory.
+    pub min_free_space_mb: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Verification {
+    /// Command and arguments, run in the root of the merged working tree. For example
+    /// ["sh", "-c", "./compile.sh && ./build/src/wl_test"].
+    pub command: Vec<String>,
+    #[serde(default = "default_verification_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_verification_timeout() -> u64 {
+    2 * 60 * 60
+}
+
+/// When branches that are no longer mentioned in any proposal get deleted. A branch is deleted as
+/// soon as either grace period is over, whichever comes first.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct Deletion {
+    /// Delete a branch after it was unmentioned in this many consecutive runs.
+    pub grace_runs: u32,
+    /// Delete a branch after it was unmentioned for this many hours, even if there were fewer runs.
+    pub grace_hours: Option<u64>,
+    /// Refuse to delete more branches than this in one run. This protects against Launchpad
+    /// temporarily returning no proposals.
+    pub max_per_run: usize,
+    /// Unique names of branches that are never deleted.
+    pub protected: Vec<String>,
+    /// Only report which branches would be deleted.
+    pub dry_run: bool,
+    /// Keep a copy of deleted GitHub branches as 'refs/archive/<slug>/<date>' on GitHub.
+    pub archive: bool,
+}
+
+impl Default for Deletion {
+    fn default() -> Self {
+        Deletion {
+            grace_runs: 3,
+            grace_hours: None,
+            max_per_run: 20,
+            protected: Vec::new(),
+            dry_run: false,
+            archive: false,
+        }
+    }
+}
+
+/// Commands that take longer than this many seconds are killed.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct Timeouts {
+    pub bzr_secs: u64,
+    pub git_secs: u64,
+    pub formatter_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            bzr_secs: 60 * 60,
+            git_secs: 60 * 60,
+            formatter_secs: 30 * 60,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join("config.json");
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let file = fs::File::open(&path).chain_err(|| "Could not open config.json.")?;
+        let this = serde_json::from_reader(file).chain_err(|| "Could not parse config.json.")?;
+        Ok(this)
+    }
+}
@@ -1,8 +1,13 @@
 use crate::subprocess::Output;
+use std::time::Duration;
 
 use error_chain::*;
 
 error_chain! {
+    foreign_links {
+        Git(git2::Error);
+    }
+
     errors {
         PidFileExists {
             description("Another bunnybot is already running.")
@@ -18,5 +23,43 @@ error_chain! {
             display("Output:\nstdout:\n{}\nstderr:\n{}\n", output.stdout, output.stderr)
         }
 
+        Timeout(command: String, timeout: Duration, output: Output) {
+            description("Process timed out.")
+            display("'{}' did not finish within {} seconds. Output:\nstdout:\n{}\nstderr:\n{}\n",
+                    command, timeout.as_secs(), output.stdout, output.stderr)
+        }
+
+        InvalidCommitMessage(reason: String) {
+            description("Invalid commit message.")
+            display("Invalid commit message: {}", reason)
+        }
+
+        VerificationFailed(output: Output) {
+            description("Verification of the merged tree failed.")
+            display("Verification of the merged tree failed. Output:\nstdout:\n{}\nstderr:\n{}\n",
+                    output.stdout, output.stderr)
+        }
+
+        MergeConflicts(paths: Vec<String>) {
+            description("Merge produced conflicts.")
+            display("Merge produced conflicts in: {}", paths.join(", "))
+        }
+
+        CannotFastForward(branch: String, upstream: String) {
+            description("Cannot fast-forward.")
+            display("Cannot fast-forward {} to {}.", branch, upstream)
+        }
+
+        PushRejected(remote: String, refs: Vec<String>) {
+            description("The push was rejected.")
+            display("Push to {} was rejected: {}", remote, refs.join(", "))
+        }
+
+        MirrorDiverged(branch: String, expected: Option<String>, actual: String) {
+            description("Somebody else pushed to the mirror.")
+            display("{} points to {}, but we last pushed {}. Not overwriting it.",
+                    branch, actual, expected.as_ref().map(|s| s.as_str()).unwrap_or("nothing"))
+        }
+
     }
 }
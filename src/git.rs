@@ -1,39 +1,318 @@
 use crate::errors::*;
 use crate::subprocess::{run_command, Verbose};
 use error_chain::bail;
+use git2::{BranchType, ObjectType, Oid, Reference, Repository};
 use std::path::Path;
 
+fn open(git_repo: &Path) -> Result<Repository> {
+    Ok(Repository::open(git_repo)?)
+}
+
 pub fn branches(git_repo: &Path) -> Result<Vec<String>> {
-    let output = run_command(&["git", "branch"], git_repo, Verbose::No)?.stdout;
+    let repo = open(git_repo)?;
     let mut branches = Vec::new();
-    for mut line in output.lines() {
-        if line.starts_with("*") {
-            line = &line[2..];
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        if let Some(name) = branch.name()? {
+            branches.push(name.to_string());
         }
-        branches.push(line.trim().to_string());
     }
     Ok(branches)
 }
 
-pub fn checkout_branch(git_repo: &Path, branch: &str) -> Result<()> {
-    run_command(&["git", "checkout", branch], git_repo, Verbose::No)?;
+/// Returns the SHA of the commit 'rev' points to.
+pub fn rev_parse(git_repo: &Path, rev: &str) -> Result<String> {
+    let repo = open(git_repo)?;
+    let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+    Ok(commit.id().to_string())
+}
+
+/// Detaches HEAD of the shared repository, so that no branch is checked out and every branch can
+/// be updated or deleted through its ref alone. The working tree is never used.
+fn detach_head(repo: &Repository) -> Result<()> {
+    // An unborn HEAD in a fresh repository has nothing to detach.
+    if let Ok(head) = repo.head() {
+        if head.is_branch() {
+            let commit = head.peel_to_commit()?.id();
+            repo.set_head_detached(commit)?;
+        }
+    }
     Ok(())
 }
 
-pub fn delete_remote_branch(git_repo: &Path, branch: &str) -> Result<()> {
+/// Creates the local branch 'branch' at the commit 'target' points to, e.g.
+/// 'refs/remotes/bzr_origin/foo'.
+pub fn create_branch(git_repo: &Path, branch: &str, target: &str) -> Result<()> {
+    let repo = open(git_repo)?;
+    let commit = repo.revparse_single(target)?.peel_to_commit()?;
+    repo.branch(branch, &commit, false)?;
+    Ok(())
+}
+
+/// Moves the branch 'branch' forward to 'upstream' by updating its ref. Fails if 'branch' has
+/// commits that are not in 'upstream'.
+pub fn fast_forward(git_repo: &Path, branch: &str, upstream: &str) -> Result<()> {
+    let repo = open(git_repo)?;
+    detach_head(&repo)?;
+    let target = repo.revparse_single(upstream)?.peel_to_commit()?.id();
+    let mut reference = repo
+        .find_branch(branch, BranchType::Local)?
+        .into_reference();
+    let current = reference.peel_to_commit()?.id();
+    if current == target {
+        return Ok(());
+    }
+    if !repo.graph_descendant_of(target, current)? {
+        bail!(ErrorKind::CannotFastForward(
+            branch.to_string(),
+            upstream.to_string()
+        ));
+    }
+    reference.set_target(target, &format!("Fast-forward {} to {}.", branch, upstream))?;
+    Ok(())
+}
+
+/// Points the branch 'branch' to 'upstream', even if that drops commits from it. This is for
+/// mirrors of branches whose history can be overwritten at the source.
+pub fn reset_branch(git_repo: &Path, branch: &str, upstream: &str) -> Result<()> {
+    let repo = open(git_repo)?;
+    detach_head(&repo)?;
+    let target = repo.revparse_single(upstream)?.peel_to_commit()?.id();
+    let mut reference = repo
+        .find_branch(branch, BranchType::Local)?
+        .into_reference();
+    if reference.peel_to_commit()?.id() != target {
+        reference.set_target(target, &format!("Reset {} to {}.", branch, upstream))?;
+    }
+    Ok(())
+}
+
+/// Returns the SHAs of the first-parent history of 'branch', oldest first. For branches mirrored
+/// through git-remote-bzr, this lines up with the bzr mainline, i.e. revno N is at index N - 1.
+pub fn first_parent_history(git_repo: &Path, branch: &str) -> Result<Vec<String>> {
+    let repo = open(git_repo)?;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push_ref(&format!("refs/heads/{}", branch))?;
+    revwalk.simplify_first_parent()?;
+    let mut history = Vec::new();
+    for oid in revwalk {
+        history.push(oid?.to_string());
+    }
+    history.reverse();
+    Ok(history)
+}
+
+/// Returns true if 'name' can be used as a tag name.
+pub fn is_valid_tag_name(name: &str) -> bool {
+    Reference::is_valid_name(&format!("refs/tags/{}", name))
+}
+
+pub fn has_tag(git_repo: &Path, name: &str) -> Result<bool> {
+    let repo = open(git_repo)?;
+    let exists = repo.find_reference(&format!("refs/tags/{}", name)).is_ok();
+    Ok(exists)
+}
+
+/// Creates the annotated tag 'name' for the commit 'sha', with the committer from the git config as
+/// the tagger.
+pub fn create_tag(git_repo: &Path, name: &str, sha: &str, message: &str) -> Result<()> {
+    let repo = open(git_repo)?;
+    let commit = repo.find_object(Oid::from_str(sha)?, Some(ObjectType::Commit))?;
+    repo.tag(name, &commit, &repo.signature()?, message, false)?;
+    Ok(())
+}
+
+/// Pushes 'refspecs' to 'remote'. Like everything that talks to a remote, this runs the git
+/// command line, which uses the ssh setup of the user and is killed after the timeout for git.
+pub fn push(git_repo: &Path, remote: &str, refspecs: &[&str]) -> Result<()> {
+    push_with_options(git_repo, remote, &[], refspecs)
+}
+
+/// Like 'push', with additional 'options' for 'git push'.
+fn push_with_options(
+    git_repo: &Path,
+    remote: &str,
+    options: &[&str],
+    refspecs: &[&str],
+) -> Result<()> {
+    let mut args = vec!["git", "push", "--porcelain"];
+    args.extend_from_slice(options);
+    args.push(remote);
+    args.extend_from_slice(refspecs);
+    match run_command(&args, git_repo, Verbose::Yes) {
+        Ok(_) => Ok(()),
+        Err(Error(ErrorKind::ProcessFailed(output), state)) => {
+            // Every reference the server refused is reported on a line starting with '!', in the
+            // form '!<tab><from>:<to><tab><summary>'.
+            let rejected = output
+                .stdout
+                .lines()
+                .filter(|line| line.starts_with('!'))
+                .map(|line| line.split('\t').skip(1).collect::<Vec<_>>().join(" "))
+                .collect::<Vec<_>>();
+            if rejected.is_empty() {
+                return Err(Error(ErrorKind::ProcessFailed(output), state));
+            }
+            bail!(ErrorKind::PushRejected(remote.to_string(), rejected))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Returns the local ref that 'reference' of 'remote' is fetched to. This is a private namespace,
+/// so that we do not interfere with the remote tracking branches.
+fn fetched_ref(remote: &str, reference: &str) -> String {
+    format!(
+        "refs/bunnybot/{}/{}",
+        remote,
+        reference.trim_start_matches("refs/")
+    )
+}
+
+/// Fetches 'reference' of 'remote', so that its commits can be inspected locally. Returns the
+/// local ref it was fetched to.
+fn fetch(git_repo: &Path, remote: &str, reference: &str) -> Result<String> {
+    let fetched = fetched_ref(remote, reference);
     run_command(
-        &["git", "push", "github", &format!(":{}", branch)],
+        &[
+            "git",
+            "fetch",
+            "--no-tags",
+            remote,
+            &format!("+{}:{}", reference, fetched),
+        ],
         git_repo,
         Verbose::No,
     )?;
-    Ok(())
+    Ok(fetched)
+}
+
+/// Returns the refs on 'remote' that start with 'prefix', e.g. 'refs/archive/', together with the
+/// SHAs they point to.
+fn ls_remote(git_repo: &Path, remote: &str, prefix: &str) -> Result<Vec<(String, String)>> {
+    let output = run_command(
+        &[
+            "git",
+            "ls-remote",
+            "--refs",
+            remote,
+            &format!("{}*", prefix),
+        ],
+        git_repo,
+        Verbose::No,
+    )?
+    .stdout;
+    // Patterns match the end of the ref names, so we filter again.
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split('\t');
+            match (parts.next(), parts.next()) {
+                (Some(sha), Some(name)) if name.starts_with(prefix) => {
+                    Some((name.to_string(), sha.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+/// Returns the SHA 'reference' points to on 'remote', or None if it does not exist there.
+pub fn remote_sha(git_repo: &Path, remote: &str, reference: &str) -> Result<Option<String>> {
+    Ok(ls_remote(git_repo, remote, reference)?
+        .into_iter()
+        .find(|(name, _)| name == reference)
+        .map(|(_, sha)| sha))
+}
+
+/// Returns the names of the refs on 'remote' that start with 'prefix', e.g. 'refs/archive/'.
+pub fn remote_refs(git_repo: &Path, remote: &str, prefix: &str) -> Result<Vec<String>> {
+    Ok(ls_remote(git_repo, remote, prefix)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// Copies 'branch' on 'remote' to 'archive_ref' on the same remote, exactly as it is there.
+/// Returns false if the branch does not exist on the remote.
+pub fn archive_remote_branch(
+    git_repo: &Path,
+    remote: &str,
+    branch: &str,
+    archive_ref: &str,
+) -> Result<bool> {
+    let reference = format!("refs/heads/{}", branch);
+    if remote_sha(git_repo, remote, &reference)?.is_none() {
+        return Ok(false);
+    }
+    let fetched = fetch(git_repo, remote, &reference)?;
+    push(git_repo, remote, &[&format!("{}:{}", fetched, archive_ref)])?;
+    Ok(true)
+}
+
+pub fn delete_remote_ref(git_repo: &Path, remote: &str, reference: &str) -> Result<()> {
+    push(git_repo, remote, &[&format!(":{}", reference)])
+}
+
+/// Force pushes the local 'branch' to 'remote', but only if the remote branch still points to
+/// 'lease', the SHA we pushed last time. Without a lease, the remote branch must not have commits
+/// that are missing locally. Otherwise somebody else pushed to the remote and we keep it intact.
+/// The server checks this atomically. Returns the pushed SHA.
+pub fn push_with_lease(
+    git_repo: &Path,
+    remote: &str,
+    branch: &str,
+    lease: Option<&str>,
+) -> Result<String> {
+    let reference = format!("refs/heads/{}", branch);
+    let local = rev_parse(git_repo, &reference)?;
+    let refspec = format!("{0}:{0}", reference);
+    let result = match lease {
+        Some(lease) => push_with_options(
+            git_repo,
+            remote,
+            &[&format!("--force-with-lease={}:{}", reference, lease)],
+            &[&refspec],
+        ),
+        // Without force, the server refuses to drop commits.
+        None => push(git_repo, remote, &[&refspec]),
+    };
+    match result {
+        Ok(()) => Ok(local),
+        Err(Error(ErrorKind::PushRejected(..), _)) => {
+            match remote_sha(git_repo, remote, &reference)? {
+                Some(actual) => bail!(ErrorKind::MirrorDiverged(
+                    format!("{}/{}", remote, branch),
+                    lease.map(|l| l.to_string()),
+                    actual
+                )),
+                // Somebody deleted the branch. There is nothing to lose in recreating it.
+                None => {
+                    push(git_repo, remote, &[&refspec])?;
+                    Ok(local)
+                }
+            }
+        }
+        Err(err) => Err(err),
+    }
+}
+
+pub fn delete_remote_branch(git_repo: &Path, branch: &str) -> Result<()> {
+    delete_remote_ref(git_repo, "github", &format!("refs/heads/{}", branch))
 }
 
 pub fn delete_local_branch(git_repo: &Path, branch: &str) -> Result<()> {
     if branch == "master" {
         bail!("Cannot delete master branch.");
     }
-    checkout_branch(git_repo, "master")?;
-    run_command(&["git", "branch", "-D", branch], git_repo, Verbose::No)?;
+    let repo = open(git_repo)?;
+    detach_head(&repo)?;
+    repo.find_branch(branch, BranchType::Local)?.delete()?;
+    Ok(())
+}
+
+pub fn delete_ref(git_repo: &Path, reference: &str) -> Result<()> {
+    let repo = open(git_repo)?;
+    repo.find_reference(reference)?.delete()?;
     Ok(())
 }
@@ -1,6 +1,9 @@
+use crate::config::Verification;
 use crate::errors::*;
 use crate::git;
-use crate::subprocess::{run_command, Verbose};
+use crate::subprocess::{run_command, run_command_with_timeout, Verbose};
+use crate::vcs::{self, Vcs};
+pub use crate::vcs::{Revision, Tag};
 use chrono::prelude::*;
 use error_chain::bail;
 use lazy_static::lazy_static;
@@ -13,7 +16,8 @@ use serde_json;
 use std::collections::HashMap;
 use std::fs;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 const API_BASE: &str = "https://api.launchpad.net";
 const LP_API: &'static str = "https://api.launchpad.net/1.0/";
@@ -21,8 +25,16 @@ const TRAVIS_ROOT: &'static str = "https://api.travis-ci.org/repos/widelands/wid
 const APPVEYOR_ROOT: &'static str = "https://ci.appveyor.\
                                      com/api/projects/widelands-dev/widelands/branch";
 
+// Directory inside the bzr repository used for throwaway checkouts. Being inside the shared
+// repository makes branching into it cheap.
+const SCRATCH_DIR: &str = ".scratch";
+const STAGING_DIR: &str = ".staging";
+
+/// The script that formats the source code, relative to the root of a branch.
+pub const FORMATTER: &str = "utils/fix_formatting.py";
+
 lazy_static! {
-    static ref SLUG_REGEX: Regex = Regex::new(r"[^A-Za-z0-9]").unwrap();
+    static ref LEGACY_SLUG_REGEX: Regex = Regex::new(r"[^A-Za-z0-9]").unwrap();
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -51,8 +63,14 @@ struct JsonCollection<T> {
 struct JsonMergeProposal {
     self_link: String,
     all_comments_collection_link: String,
-    source_branch_link: String,
-    target_branch_link: String,
+    // Set for proposals between bzr branches.
+    source_branch_link: Option<String>,
+    target_branch_link: Option<String>,
+    // Set for proposals between branches in git repositories.
+    source_git_repository_link: Option<String>,
+    source_git_path: Option<String>,
+    target_git_repository_link: Option<String>,
+    target_git_path: Option<String>,
     commit_message: Option<String>,
 }
 
@@ -65,14 +83,20 @@ struct JsonBranch {
 
 #[derive(serde::Deserialize, Debug)]
 pub struct Comment {
+    pub self_link: String,
     pub message_body: String,
+    pub date_created: String,
 }
 
+/// The SHA we last pushed to each branch of the GitHub mirror, by branch name.
+pub type Leases = HashMap<String, String>;
+
 #[derive(Debug)]
 pub struct Branch {
     // For example: ~widelands-dev/widelands/trunk
     pub unique_name: String,
     pub slug: String,
+    vcs: &'static dyn Vcs,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -118,8 +142,165 @@ struct JsonAppveyorBranch {
     version: String,
 }
 
+/// Runs the verification command in the working tree at 'path'.
+fn verify(path: &Path, verification: &Verification) -> Result<()> {
+    let args = verification
+        .command
+        .iter()
+        .map(|a| a.as_str())
+        .collect::<Vec<_>>();
+    match run_command_with_timeout(
+        &args,
+        path,
+        Verbose::Yes,
+        Duration::from_secs(verification.timeout_secs),
+    ) {
+        Ok(_) => Ok(()),
+        Err(Error(ErrorKind::ProcessFailed(output), _))
+        | Err(Error(ErrorKind::Timeout(_, _, output), _)) => {
+            bail!(ErrorKind::VerificationFailed(output))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// One revision as shown by the log of a branch.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub revno: i32,
+    pub revision_id: String,
+    pub author: String,
+    /// First line of the commit message.
+    pub summary: String,
+}
+
+/// Parses the output of 'bzr log --long --levels=1 --show-ids'. Entries are separated by a line of
+/// dashes and consist of 'key: value' lines, followed by the indented commit message.
+pub fn parse_bzr_log(output: &str) -> Vec<LogEntry> {
+    let mut entries = Vec::new();
+    for block in output.split("\n---") {
+        let mut revno = None;
+        let mut revision_id = None;
+        let mut committer = None;
+        let mut author = None;
+        let mut summary = None;
+        let mut in_message = false;
+        for line in block.lines() {
+            if in_message {
+                if summary.is_none() && !line.trim().is_empty() {
+                    summary = Some(line.trim().to_string());
+                }
+                continue;
+            }
+            // Strip the email address, 'Jane Doe <jane@example.com>' becomes 'Jane Doe'.
+            let name = |value: &str| value.split(" <").next().unwrap().trim().to_string();
+            if let Some(value) = line.strip_prefix("revno:") {
+                // Merge revisions look like 'revno: 8800 [merge]'.
+                revno = value.split_whitespace().next().and_then(|r| r.parse().ok());
+            } else if let Some(value) = line.strip_prefix("revision-id:") {
+                revision_id = Some(value.trim().to_string());
+            } else if let Some(value) = line.strip_prefix("committer:") {
+                committer = Some(name(value));
+            } else if let Some(value) = line.strip_prefix("author:") {
+                author = Some(name(value));
+            } else if let Some(value) = line.strip_prefix("authors:") {
+                // Several authors are separated by commas, we only show the first.
+                author = Some(name(value.split(',').next().unwrap()));
+            } else if line.starts_with("message:") {
+                in_message = true;
+            }
+        }
+        if let (Some(revno), Some(revision_id)) = (revno, revision_id) {
+            entries.push(LogEntry {
+                revno,
+                revision_id,
+                author: author.or(committer).unwrap_or_default(),
+                summary: summary.unwrap_or_default(),
+            });
+        }
+    }
+    entries
+}
+
+/// Builds the message for committing a merge of 'source'. The optional 'commit_message' is
+/// appended after a summary line and must have a non-empty first line of reasonable length.
+pub fn build_commit_message(source: &Branch, commit_message: Option<&str>) -> Result<String> {
+    const MAX_SUMMARY_LENGTH: usize = 120;
+    const MAX_LENGTH: usize = 5000;
+
+    let mut full_commit_message = format!("Merged lp:{}", source.unique_name);
+    let commit_message = match commit_message {
+        Some(commit_message) => commit_message.trim_end(),
+        None => {
+            full_commit_message.push('.');
+            return Ok(full_commit_message);
+        }
+    };
+
+    let summary = commit_message.lines().next().unwrap_or("");
+    if summary.trim().is_empty() {
+        bail!(ErrorKind::InvalidCommitMessage(
+            "The first line must not be empty.".to_string()
+        ));
+    }
+    if summary.chars().count() > MAX_SUMMARY_LENGTH {
+        bail!(ErrorKind::InvalidCommitMessage(format!(
+            "The first line is longer than {} characters.",
+            MAX_SUMMARY_LENGTH
+        )));
+    }
+    if commit_message.chars().count() > MAX_LENGTH {
+        bail!(ErrorKind::InvalidCommitMessage(format!(
+            "The message is longer than {} characters.",
+            MAX_LENGTH
+        )));
+    }
+    full_commit_message.push_str(":\n");
+    full_commit_message.push_str(commit_message);
+    Ok(full_commit_message)
+}
+
+/// Turns a unique name into a name that can be used for directories, git branches and CI builds.
+/// Letters, digits and '-' are kept, every other byte is escaped as '_' followed by its value in
+/// hex. This way, different unique names never share a slug and 'unslugify' can reverse it.
 pub fn slugify(branch: &str) -> String {
-    SLUG_REGEX.replace_all(&branch, "_").to_string()
+    let mut slug = String::new();
+    for byte in branch.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' {
+            slug.push(byte as char);
+        } else {
+            slug.push_str(&format!("_{:02X}", byte));
+        }
+    }
+    slug
+}
+
+/// Returns the unique name 'slug' was created from, or None if it is not a valid slug.
+pub fn unslugify(slug: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = slug;
+    while let Some(c) = rest.chars().next() {
+        if c == '_' {
+            let hex = rest.get(1..3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &rest[3..];
+        } else if c.is_ascii_alphanumeric() || c == '-' {
+            bytes.push(c as u8);
+            rest = &rest[1..];
+        } else {
+            return None;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The slugs before they were made unique, which replaced every special character with '_'. Only
+/// needed to migrate old data directories.
+pub fn legacy_slugify(branch: &str) -> String {
+    LEGACY_SLUG_REGEX.replace_all(&branch, "_").to_string()
 }
 
 impl Branch {
@@ -128,14 +309,47 @@ impl Branch {
         Branch::from_unique_name(url.split_at(LP_API.len()).1)
     }
 
+    /// Returns the branch on one side of a merge proposal, which is either a bzr branch or a
+    /// branch in a git repository.
+    fn from_lp_api_links(
+        branch_link: Option<&str>,
+        git_repository_link: Option<&str>,
+        git_path: Option<&str>,
+    ) -> Result<Self> {
+        match (branch_link, git_repository_link, git_path) {
+            (Some(branch_link), _, _) => Ok(Branch::from_lp_api_link(branch_link)),
+            (None, Some(repository_link), Some(path)) if repository_link.starts_with(LP_API) => Ok(
+                Branch::from_git_repository(repository_link.split_at(LP_API.len()).1, path),
+            ),
+            _ => bail!("Merge proposal names neither a bzr branch nor a git branch."),
+        }
+    }
+
     pub fn from_unique_name(unique_name: &str) -> Self {
         let slug = slugify(&unique_name);
         Branch {
             unique_name: unique_name.to_string(),
             slug: slug,
+            vcs: &vcs::BZR,
         }
     }
 
+    /// A branch in a Launchpad git repository, e.g. 'repository' could be
+    /// '~widelands-dev/widelands/+git/widelands' and 'path' 'refs/heads/master'.
+    pub fn from_git_repository(repository: &str, path: &str) -> Self {
+        let name = path.trim_start_matches("refs/heads/");
+        let unique_name = format!("{}:{}", repository, name);
+        Branch {
+            slug: slugify(&unique_name),
+            unique_name,
+            vcs: &vcs::GIT,
+        }
+    }
+
+    fn path(&self, bzr_repo: &Path) -> PathBuf {
+        bzr_repo.join(&self.slug)
+    }
+
     /// Returns true if the branch changed.
     pub fn update(&self, bzr_repo: &Path) -> Result<bool> {
         if !self.is_branched(bzr_repo) {
@@ -145,83 +359,109 @@ impl Branch {
         self.pull(bzr_repo)
     }
 
-    fn is_branched(&self, bzr_repo: &Path) -> bool {
-        bzr_repo.join(Path::new(&self.slug)).exists()
+    pub fn is_branched(&self, bzr_repo: &Path) -> bool {
+        self.path(bzr_repo).exists()
     }
 
     fn branch(&self, bzr_repo: &Path) -> Result<()> {
-        run_command(
-            &[
-                "bzr",
-                "branch",
-                &format!("lp:{}", self.unique_name),
-                &self.slug,
-            ],
-            bzr_repo,
-            Verbose::Yes,
-        )?;
-        Ok(())
+        self.vcs.branch(&self.unique_name, &self.path(bzr_repo))
     }
 
     /// Revert the changes in the branch (if any).
     fn revert(&self, bzr_repo: &Path) -> Result<()> {
-        run_command(&["bzr", "revert"], &bzr_repo.join(&self.slug), Verbose::Yes)?;
-        run_command(
-            &["bzr", "clean-tree", "--unknown", "--detritus", "--force"],
-            &bzr_repo.join(&self.slug),
-            Verbose::Yes,
-        )?;
-        Ok(())
+        self.vcs.revert(&self.path(bzr_repo))
     }
 
-    /// Returns true if the branch changed.
+    /// Returns true if the branch changed. We compare the tip revision ids, since an overwriting
+    /// pull can replace history with a different tip at the same revno.
     fn pull(&self, bzr_repo: &Path) -> Result<bool> {
-        let before = self.revno(bzr_repo)?;
+        let before = self.revision(bzr_repo)?.revision_id;
         self.revert(bzr_repo)?;
-        run_command(
-            &["bzr", "pull", "--overwrite"],
-            &bzr_repo.join(&self.slug),
-            Verbose::Yes,
-        )?;
-        Ok(before != self.revno(bzr_repo)?)
+        self.vcs.pull(&self.path(bzr_repo))?;
+        Ok(before != self.revision(bzr_repo)?.revision_id)
+    }
+
+    /// Test merges 'source' into a scratch copy of this branch, leaving the real checkout
+    /// untouched. Returns the conflicting paths, which is empty if the merge is clean.
+    pub fn check_merge(&self, bzr_repo: &Path, source: &Branch) -> Result<Vec<String>> {
+        let scratch = bzr_repo.join(SCRATCH_DIR);
+        if scratch.exists() {
+            fs::remove_dir_all(&scratch).chain_err(|| "Could not delete scratch directory.")?;
+        }
+        self.vcs.copy(&self.path(bzr_repo), &scratch)?;
+        let result = match self.vcs.merge(&scratch, &source.path(bzr_repo)) {
+            Ok(()) => Ok(Vec::new()),
+            Err(Error(ErrorKind::MergeConflicts(paths), _)) => Ok(paths),
+            Err(err) => Err(err),
+        };
+        // Ignoring errors. The next check starts by deleting the directory anyways.
+        let _ = fs::remove_dir_all(&scratch);
+        result
     }
 
-    fn revno(&self, bzr_repo: &Path) -> Result<i32> {
+    pub fn revno(&self, bzr_repo: &Path) -> Result<i32> {
         assert!(self.is_branched(bzr_repo));
-        let output =
-            run_command(&["bzr", "revno"], &bzr_repo.join(&self.slug), Verbose::No)?.stdout;
-        let revno = output.trim().parse().unwrap();
-        Ok(revno)
+        self.vcs.revno(&self.path(bzr_repo))
     }
 
-    pub fn update_git(&self, git_repo: &Path) -> Result<()> {
-        run_command(
-            &["git", "config", "remote-bzr.branches", &self.slug],
-            git_repo,
-            Verbose::Yes,
-        )?;
-        run_command(&["git", "fetch", "bzr_origin"], git_repo, Verbose::Yes)?;
+    /// Throws away the local bzr branch and git mirror and recreates both from Launchpad. Returns
+    /// the SHA of the freshly pushed git commit. The GitHub branch is overwritten even if somebody
+    /// else pushed to it: unlike regular updates, a resync is explicitly requested to make the
+    /// mirror match Launchpad again.
+    pub fn resync(&self, bzr_repo: &Path, git_repo: &Path, leases: &mut Leases) -> Result<String> {
+        if self.is_branched(bzr_repo) {
+            fs::remove_dir_all(self.path(bzr_repo))
+                .chain_err(|| format!("Could not delete bzr branch {}.", self.slug))?;
+        }
+        if git::branches(git_repo)?.contains(&self.slug) {
+            git::delete_local_branch(git_repo, &self.slug)?;
+        }
+        // The remote tracking branch might be broken as well, so we let git-remote-bzr recreate it.
+        let _ = git::delete_ref(git_repo, &format!("refs/remotes/bzr_origin/{}", self.slug));
+
+        self.branch(bzr_repo)?;
+        // Taking whatever is on GitHub now as the lease turns the push into a forced one.
+        let reference = format!("refs/heads/{}", self.slug);
+        match git::remote_sha(git_repo, "github", &reference)? {
+            Some(sha) => leases.insert(self.slug.clone(), sha),
+            None => leases.remove(&self.slug),
+        };
+        self.update_git(git_repo, leases)?;
+        git::rev_parse(git_repo, &self.slug)
+    }
+
+    /// Returns the revno and revision id of the tip of the branch.
+    pub fn revision(&self, bzr_repo: &Path) -> Result<Revision> {
+        assert!(self.is_branched(bzr_repo));
+        self.vcs.revision(&self.path(bzr_repo))
+    }
 
+    pub fn tags(&self, bzr_repo: &Path) -> Result<Vec<Tag>> {
+        assert!(self.is_branched(bzr_repo));
+        self.vcs.tags(&self.path(bzr_repo))
+    }
+
+    /// Returns the newest revisions on the mainline of the branch, newest first.
+    pub fn log(&self, bzr_repo: &Path, limit: usize) -> Result<Vec<LogEntry>> {
+        assert!(self.is_branched(bzr_repo));
+        self.vcs.log(&self.path(bzr_repo), limit)
+    }
+
+    /// Mirrors the branch to GitHub. 'leases' holds the SHAs we last pushed for each branch on
+    /// GitHub and is updated after pushing.
+    pub fn update_git(&self, git_repo: &Path, leases: &mut Leases) -> Result<()> {
+        let upstream = self
+            .vcs
+            .fetch_into_mirror(&self.unique_name, &self.slug, git_repo)?;
         if !git::branches(git_repo)?.contains(&self.slug) {
-            run_command(
-                &[
-                    "git",
-                    "branch",
-                    "--track",
-                    &self.slug,
-                    &format!("bzr_origin/{}", self.slug),
-                ],
-                git_repo,
-                Verbose::Yes,
-            )?;
+            git::create_branch(git_repo, &self.slug, &upstream)?;
         }
-        git::checkout_branch(git_repo, &self.slug)?;
-        run_command(&["git", "pull"], git_repo, Verbose::Yes)?;
-        run_command(
-            &["git", "push", "github", &self.slug, "--force"],
-            git_repo,
-            Verbose::Yes,
-        )?;
+        // The history on Launchpad is what counts, even if it was overwritten. The lease keeps
+        // us from overwriting foreign pushes to GitHub.
+        git::reset_branch(git_repo, &self.slug, &upstream)?;
+        let lease = leases.get(&self.slug).map(|s| s.as_str());
+        let sha = git::push_with_lease(git_repo, "github", &self.slug, lease)?;
+        leases.insert(self.slug.clone(), sha);
         Ok(())
     }
 
@@ -246,77 +486,136 @@ impl Branch {
     }
 
     fn push(&self, bzr_repo: &Path) -> Result<()> {
-        let path = bzr_repo.join(&self.slug);
-        run_command(
-            &["bzr", "push", ":parent", "--overwrite"],
-            &path,
-            Verbose::Yes,
-        )?;
-        Ok(())
+        self.vcs.push(&self.path(bzr_repo))
     }
 
-    fn fix_formatting(&self, bzr_repo: &Path, commit: bool) -> Result<()> {
-        const FORMATTING: &'static str = "utils/fix_formatting.py";
-        let path = bzr_repo.join(&self.slug);
-        if !path.join(FORMATTING).exists() {
-            println!("Did not find {}. Not trying to run it.", FORMATTING);
-            return Ok(());
+    /// Like 'push', but fails instead of overwriting commits that are only on Launchpad. Used for
+    /// source branches, which belong to their authors.
+    fn push_without_overwrite(&self, bzr_repo: &Path) -> Result<()> {
+        self.vcs
+            .push_to(&self.path(bzr_repo), &self.unique_name)
+            .chain_err(|| {
+                format!(
+                    "Could not push to lp:{}. Did it get new commits in the meantime?",
+                    self.unique_name
+                )
+            })
+    }
+
+    /// Runs the formatting script over the branch. If 'commit' is true, the changes are committed
+    /// and the paths of the files that changed are returned.
+    fn fix_formatting(&self, bzr_repo: &Path, commit: bool) -> Result<Vec<String>> {
+        let path = self.path(bzr_repo);
+        if !path.join(FORMATTER).exists() {
+            println!("Did not find {}. Not trying to run it.", FORMATTER);
+            return Ok(Vec::new());
         }
-        run_command(&[FORMATTING], &path, Verbose::Yes)?;
+        run_command(&[FORMATTER], &path, Verbose::Yes)?;
         if !commit {
-            return Ok(());
+            return Ok(Vec::new());
         }
 
-        let result = run_command(
-            &["bzr", "commit", "-m", "Fix formatting."],
-            &path,
-            Verbose::Yes,
-        );
-        match result {
-            Ok(_) => Ok(()),
-            Err(Error(ErrorKind::ProcessFailed(output), _)) => {
-                // If there is nothing to commit, the codecheck didn't change anything. This is no
-                // error.
-                if output.stdout.find("No changes to commit.").is_none() {
-                    Err(ErrorKind::ProcessFailed(output).into())
-                } else {
-                    Ok(())
-                }
-            }
-            Err(err) => Err(err),
+        // If nothing changed, the codecheck didn't change anything. This is no error.
+        let changed_files = self.vcs.changed_files(&path)?;
+        if !changed_files.is_empty() {
+            self.vcs.commit(&path, "Fix formatting.")?;
         }
+        Ok(changed_files)
     }
 
     fn merge_source(
         &self,
         bzr_repo: &Path,
         source: &Branch,
-        commit_message: &Option<String>,
+        commit_message: Option<&str>,
+        verification: Option<&Verification>,
+    ) -> Result<Revision> {
+        self.merge_into_target(bzr_repo, source, commit_message, verification)?;
+        self.push(bzr_repo)?;
+        self.revision(bzr_repo)
+    }
+
+    /// Like 'merge_source', but does not push the result. The merged tree is formatted and, if
+    /// 'verification' is given, verified before it is committed.
+    fn merge_into_target(
+        &self,
+        bzr_repo: &Path,
+        source: &Branch,
+        commit_message: Option<&str>,
+        verification: Option<&Verification>,
     ) -> Result<()> {
-        let target_path = bzr_repo.join(&self.slug);
-        run_command(
-            &["bzr", "merge", &format!("../{}", source.slug)],
-            &target_path,
-            Verbose::Yes,
-        )?;
+        let full_commit_message = build_commit_message(source, commit_message)?;
+        let target_path = self.path(bzr_repo);
+        self.vcs.merge(&target_path, &source.path(bzr_repo))?;
 
         self.fix_formatting(bzr_repo, false)?;
+        if let Some(verification) = verification {
+            verify(&target_path, verification)?;
+        }
 
-        let mut full_commit_message = format!("Merged lp:{}", source.unique_name);
-        if let Some(ref commit_message) = *commit_message {
-            full_commit_message.push_str(":\n");
-            full_commit_message.push_str(commit_message);
-        } else {
-            full_commit_message.push_str(".");
+        self.vcs.commit(&target_path, &full_commit_message)
+    }
+
+    /// Merges 'source' and commits it with the standard message. Unlike 'merge_into_target', the
+    /// tree is not formatted, so that the commit contains nothing but the merge.
+    fn merge_and_commit(&self, bzr_repo: &Path, source: &Branch) -> Result<()> {
+        let full_commit_message = build_commit_message(source, None)?;
+        let path = self.path(bzr_repo);
+        self.vcs.merge(&path, &source.path(bzr_repo))?;
+        self.vcs.commit(&path, &full_commit_message)
+    }
+
+    /// Creates a throwaway copy of this branch, in which several proposals can be merged and
+    /// verified together before they are published with 'publish_staging'.
+    pub fn create_staging(&self, bzr_repo: &Path) -> Result<Branch> {
+        let staging = Branch {
+            unique_name: self.unique_name.clone(),
+            slug: STAGING_DIR.to_string(),
+            vcs: self.vcs,
+        };
+        staging.discard_staging(bzr_repo)?;
+        self.vcs
+            .copy(&self.path(bzr_repo), &staging.path(bzr_repo))?;
+        Ok(staging)
+    }
+
+    pub fn discard_staging(&self, bzr_repo: &Path) -> Result<()> {
+        assert_eq!(self.slug, STAGING_DIR);
+        if self.is_branched(bzr_repo) {
+            fs::remove_dir_all(self.path(bzr_repo))
+                .chain_err(|| "Could not delete staging directory.")?;
         }
-        run_command(
-            &["bzr", "commit", "-m", &full_commit_message],
-            &target_path,
-            Verbose::Yes,
-        )?;
-        self.push(bzr_repo)?;
         Ok(())
     }
+
+    /// Runs the verification command against the working tree.
+    pub fn verify(&self, bzr_repo: &Path, verification: &Verification) -> Result<()> {
+        verify(&self.path(bzr_repo), verification)
+    }
+
+    /// Pushes a staging copy to Launchpad. This fails if the branch on Launchpad moved since the
+    /// copy was created.
+    pub fn publish_staging(&self, bzr_repo: &Path) -> Result<()> {
+        assert_eq!(self.slug, STAGING_DIR);
+        self.vcs.push_to(&self.path(bzr_repo), &self.unique_name)
+    }
+
+    /// Backs out 'revision' by reverse merging it, then commits and pushes the result.
+    fn revert_revision(&self, bzr_repo: &Path, revision: &Revision, source: &Branch) -> Result<()> {
+        let target_path = self.path(bzr_repo);
+        self.vcs.reverse_merge(&target_path, revision)?;
+        self.vcs
+            .commit(&target_path, &format!("Revert lp:{}.", source.unique_name))?;
+        self.push(bzr_repo)
+    }
+}
+
+/// Returns how Launchpad names 'branch' in emails. 'branch_link' is the API link of bzr branches.
+fn identity(branch: &Branch, branch_link: Option<&str>) -> Result<String> {
+    match branch_link {
+        Some(branch_link) => Ok(get::<JsonBranch>(branch_link)?.bzr_identity),
+        None => Ok(branch.unique_name.clone()),
+    }
 }
 
 #[derive(Debug)]
@@ -326,8 +625,9 @@ pub struct MergeProposal {
     pub comments: Vec<Comment>,
     commit_message: Option<String>,
     self_link: String,
-    source_branch_link: String,
-    target_branch_link: String,
+    // API links of the bzr branches. None for branches in git repositories.
+    source_branch_link: Option<String>,
+    target_branch_link: Option<String>,
 }
 
 impl MergeProposal {
@@ -335,9 +635,17 @@ impl MergeProposal {
         let comments = get::<JsonCollection<Comment>>(&json.all_comments_collection_link)?.entries;
 
         let merge_proposal = MergeProposal {
-            source_branch: Branch::from_lp_api_link(&json.source_branch_link),
+            source_branch: Branch::from_lp_api_links(
+                json.source_branch_link.as_deref(),
+                json.source_git_repository_link.as_deref(),
+                json.source_git_path.as_deref(),
+            )?,
             source_branch_link: json.source_branch_link,
-            target_branch: Branch::from_lp_api_link(&json.target_branch_link),
+            target_branch: Branch::from_lp_api_links(
+                json.target_branch_link.as_deref(),
+                json.target_git_repository_link.as_deref(),
+                json.target_git_path.as_deref(),
+            )?,
             target_branch_link: json.target_branch_link,
             commit_message: json.commit_message,
             comments: comments,
@@ -347,15 +655,13 @@ impl MergeProposal {
     }
 
     pub fn add_comment(&self, credentials: &Credentials, comment: &str) -> Result<()> {
-        let source_branch_json = get::<JsonBranch>(&self.source_branch_link)?;
-        let target_branch_json = get::<JsonBranch>(&self.target_branch_link)?;
-
         // This subject is what Launchpad currently uses for sending out their email. We want to
         // use the same, so that threads are not broken in email clients, but Launchpad offers no
         // API.
         let subject = format!(
             "[Merge] {} into {}",
-            source_branch_json.bzr_identity, target_branch_json.bzr_identity
+            identity(&self.source_branch, self.source_branch_link.as_deref())?,
+            identity(&self.target_branch, self.target_branch_link.as_deref())?
         );
 
         let mut values = HashMap::new();
@@ -366,17 +672,118 @@ impl MergeProposal {
             .chain_err(|| ErrorKind::Http(self.self_link.clone()))
     }
 
-    pub fn merge(&self, bzr_repo: &Path) -> Result<()> {
+    /// Merges the target branch into the source branch and pushes the result to Launchpad, so
+    /// that long running proposals catch up with trunk.
+    pub fn update_source(
+        &self,
+        bzr_repo: &Path,
+        git_repo: &Path,
+        leases: &mut Leases,
+    ) -> Result<()> {
+        self.target_branch.update(bzr_repo)?;
+        let result = self
+            .source_branch
+            .merge_and_commit(bzr_repo, &self.target_branch)
+            .and_then(|_| self.source_branch.push_without_overwrite(bzr_repo));
+        if let Err(err) = result {
+            // Ignoring errors. We just try to clean up.
+            let _ = self.source_branch.revert(bzr_repo);
+            return Err(err);
+        }
+        self.source_branch.update_git(git_repo, leases)
+    }
+
+    /// Runs the formatting script on the source branch and pushes the result to Launchpad. Returns
+    /// the files that were changed.
+    pub fn fix_formatting(
+        &self,
+        bzr_repo: &Path,
+        git_repo: &Path,
+        leases: &mut Leases,
+    ) -> Result<Vec<String>> {
+        let result = self.source_branch.fix_formatting(bzr_repo, true);
+        let changed_files = match result {
+            Ok(changed_files) => changed_files,
+            Err(err) => {
+                // Ignoring errors. We just try to clean up.
+                let _ = self.source_branch.revert(bzr_repo);
+                return Err(err);
+            }
+        };
+        if !changed_files.is_empty() {
+            self.source_branch.push_without_overwrite(bzr_repo)?;
+            self.source_branch.update_git(git_repo, leases)?;
+        }
+        Ok(changed_files)
+    }
+
+    /// Merges the proposal into its target. 'commit_message' overrides the commit message of the
+    /// proposal. If 'verification' is given, the merged tree is only committed if it passes.
+    /// Returns the revision of the target that contains the merge.
+    pub fn merge(
+        &self,
+        bzr_repo: &Path,
+        commit_message: Option<&str>,
+        verification: Option<&Verification>,
+    ) -> Result<Revision> {
+        self.target_branch.update(bzr_repo)?;
+        let conflicts = self
+            .target_branch
+            .check_merge(bzr_repo, &self.source_branch)?;
+        if !conflicts.is_empty() {
+            bail!(ErrorKind::MergeConflicts(conflicts));
+        }
+        let commit_message = commit_message.or(self.commit_message.as_deref());
+        let result = self.target_branch.merge_source(
+            bzr_repo,
+            &self.source_branch,
+            commit_message,
+            verification,
+        );
+        if result.is_err() {
+            // Ignoring errors. We just try to clean up.
+            let _ = self.target_branch.revert(bzr_repo);
+        }
+        result
+    }
+
+    /// Merges the proposal into 'staging', a copy of the target created by
+    /// 'Branch::create_staging'. On failure, 'staging' is reverted to where it was before. Returns
+    /// the revision of the merge in 'staging'.
+    pub fn merge_into_staging(
+        &self,
+        bzr_repo: &Path,
+        staging: &Branch,
+        commit_message: Option<&str>,
+    ) -> Result<Revision> {
+        let commit_message = commit_message.or(self.commit_message.as_deref());
+        let result = staging
+            .merge_into_target(bzr_repo, &self.source_branch, commit_message, None)
+            .and_then(|_| staging.revision(bzr_repo));
+        if result.is_err() {
+            // Ignoring errors. We just try to clean up.
+            let _ = staging.revert(bzr_repo);
+        }
+        result
+    }
+
+    /// Backs out 'revision', which merged this proposal, from the target branch.
+    pub fn revert(&self, bzr_repo: &Path, revision: &Revision) -> Result<()> {
         self.target_branch.update(bzr_repo)?;
-        let result =
-            self.target_branch
-                .merge_source(bzr_repo, &self.source_branch, &self.commit_message);
+        let result = self
+            .target_branch
+            .revert_revision(bzr_repo, revision, &self.source_branch);
         if result.is_err() {
             // Ignoring errors. We just try to clean up.
             let _ = self.target_branch.revert(bzr_repo);
         }
         result
     }
+
+    /// The API link of this proposal, which can be passed to 'get_merge_proposal'.
+    pub fn self_link(&self) -> &str {
+        &self.self_link
+    }
 }
 
 fn get<D>(url: &str) -> Result<D>
@@ -430,6 +837,10 @@ fn build_oauth_str(credentials: &Credentials) -> String {
     )
 }
 
+pub fn get_merge_proposal(self_link: &str) -> Result<MergeProposal> {
+    MergeProposal::from_json(get::<JsonMergeProposal>(self_link)?)
+}
+
 pub fn get_merge_proposals(name: &str) -> Result<Vec<MergeProposal>> {
     let url = format!(
         "{}{}?ws.op=getMergeProposals&status=Needs review",
@@ -441,3 +852,160 @@ pub fn get_merge_proposals(name: &str) -> Result<Vec<MergeProposal>> {
     }
     Ok(entries)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> Branch {
+        Branch::from_unique_name("~a/widelands/foo")
+    }
+
+    fn invalid_reason(commit_message: &str) -> String {
+        match build_commit_message(&source(), Some(commit_message)) {
+            Err(Error(ErrorKind::InvalidCommitMessage(reason), _)) => reason,
+            other => panic!("Expected an invalid commit message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn git_branches() {
+        let branch = Branch::from_git_repository("~a/widelands/+git/wl", "refs/heads/foo");
+        assert_eq!(branch.unique_name, "~a/widelands/+git/wl:foo");
+        assert_eq!(
+            unslugify(&branch.slug).as_deref(),
+            Some("~a/widelands/+git/wl:foo")
+        );
+        assert_ne!(
+            branch.slug,
+            Branch::from_unique_name("~a/widelands/foo").slug
+        );
+    }
+
+    #[test]
+    fn merge_proposal_branches() {
+        let json = serde_json::from_str::<JsonMergeProposal>(&format!(
+            r#"{{
+                "self_link": "{0}~a/widelands/+git/wl/+merge/1",
+                "all_comments_collection_link": "{0}~a/widelands/+git/wl/+merge/1/all_comments",
+                "source_branch_link": null,
+                "target_branch_link": null,
+                "source_git_repository_link": "{0}~a/widelands/+git/wl",
+                "source_git_path": "refs/heads/foo",
+                "target_git_repository_link": "{0}~widelands-dev/widelands/+git/widelands",
+                "target_git_path": "refs/heads/master",
+                "commit_message": null
+            }}"#,
+            LP_API
+        ))
+        .unwrap();
+        let source = Branch::from_lp_api_links(
+            json.source_branch_link.as_deref(),
+            json.source_git_repository_link.as_deref(),
+            json.source_git_path.as_deref(),
+        )
+        .unwrap();
+        assert_eq!(source.unique_name, "~a/widelands/+git/wl:foo");
+        let target = Branch::from_lp_api_links(
+            json.target_branch_link.as_deref(),
+            json.target_git_repository_link.as_deref(),
+            json.target_git_path.as_deref(),
+        )
+        .unwrap();
+        assert_eq!(
+            target.unique_name,
+            "~widelands-dev/widelands/+git/widelands:master"
+        );
+
+        let bzr =
+            Branch::from_lp_api_links(Some(&format!("{}~a/widelands/foo", LP_API)), None, None)
+                .unwrap();
+        assert_eq!(bzr.unique_name, "~a/widelands/foo");
+        assert!(Branch::from_lp_api_links(None, None, Some("refs/heads/foo")).is_err());
+    }
+
+    #[test]
+    fn slugs_are_unique() {
+        assert_eq!(
+            slugify("~a/widelands/foo-bar"),
+            "_7Ea_2Fwidelands_2Ffoo-bar"
+        );
+        assert_eq!(
+            slugify("~a/widelands/foo_bar"),
+            "_7Ea_2Fwidelands_2Ffoo_5Fbar"
+        );
+        // Both used to be '_a_widelands_foo_bar'.
+        assert_eq!(
+            legacy_slugify("~a/widelands/foo-bar"),
+            legacy_slugify("~a/widelands/foo_bar")
+        );
+    }
+
+    #[test]
+    fn slugs_round_trip() {
+        for name in &[
+            "~a/widelands/foo-bar",
+            "~a/widelands/foo_bar",
+            "~widelands-dev/widelands/trunk",
+            "~ä/widelands/straße",
+        ] {
+            let slug = slugify(name);
+            assert!(slug
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'));
+            assert_eq!(unslugify(&slug).as_deref(), Some(*name));
+        }
+    }
+
+    #[test]
+    fn invalid_slugs() {
+        assert_eq!(unslugify("foo_"), None);
+        assert_eq!(unslugify("foo_7"), None);
+        assert_eq!(unslugify("foo_+7"), None);
+        assert_eq!(unslugify("foo.bar"), None);
+        // A lone byte of a multi-byte character.
+        assert_eq!(unslugify("_C3"), None);
+    }
+
+    #[test]
+    fn commit_message_without_override() {
+        assert_eq!(
+            build_commit_message(&source(), None).unwrap(),
+            "Merged lp:~a/widelands/foo."
+        );
+    }
+
+    #[test]
+    fn commit_message_with_override() {
+        assert_eq!(
+            build_commit_message(&source(), Some("Fix the crash.\n\nDetails.\n")).unwrap(),
+            "Merged lp:~a/widelands/foo:\nFix the crash.\n\nDetails."
+        );
+    }
+
+    #[test]
+    fn commit_message_with_empty_first_line() {
+        assert_eq!(
+            invalid_reason("\nFix the crash."),
+            "The first line must not be empty."
+        );
+    }
+
+    #[test]
+    fn commit_message_with_long_first_line() {
+        assert!(build_commit_message(&source(), Some(&"ä".repeat(120))).is_ok());
+        assert_eq!(
+            invalid_reason(&"a".repeat(121)),
+            "The first line is longer than 120 characters."
+        );
+    }
+
+    #[test]
+    fn commit_message_with_long_body() {
+        let message = format!("Fix the crash.\n{}", "a".repeat(5000));
+        assert_eq!(
+            invalid_reason(&message),
+            "The message is longer than 5000 characters."
+        );
+    }
+}
@@ -1,7 +1,11 @@
 #![recursion_limit = "1024"]
 
+pub mod config;
 pub mod errors;
 pub mod git;
 pub mod launchpad;
+pub mod maintenance;
 pub mod pidfile;
+pub mod revision_map;
 pub mod subprocess;
+pub mod vcs;
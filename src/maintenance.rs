@@ -0,0 +1,78 @@
+use crate::errors::*;
+use crate::subprocess::{run_command, Verbose};
+use error_chain::bail;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// Returns the names of the branch directories in 'bzr_repo'. Hidden directories like .bzr or the
+/// scratch space for test merges are skipped.
+fn branch_dirs(bzr_repo: &Path) -> Result<Vec<String>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(bzr_repo).chain_err(|| "Could not read bzr_repo.")? {
+        let path = entry.chain_err(|| "Could not read bzr_repo.")?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if path.is_dir() && !name.starts_with('.') {
+            dirs.push(name);
+        }
+    }
+    Ok(dirs)
+}
+
+/// Returns the disk usage of 'path' in kilobytes.
+pub fn disk_usage(path: &Path) -> Result<u64> {
+    let output = run_command(&["du", "-sk", "."], path, Verbose::No)?.stdout;
+    match output
+        .split_whitespace()
+        .next()
+        .and_then(|s| s.parse().ok())
+    {
+        Some(kb) => Ok(kb),
+        None => bail!("Could not parse du output: {}", output),
+    }
+}
+
+/// Returns the disk usage in kilobytes of every branch in 'bzr_repo', largest first.
+pub fn branch_disk_usage(bzr_repo: &Path) -> Result<Vec<(String, u64)>> {
+    let mut usage = Vec::new();
+    for name in branch_dirs(bzr_repo)? {
+        let kb = disk_usage(&bzr_repo.join(&name))?;
+        usage.push((name, kb));
+    }
+    usage.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(usage)
+}
+
+/// Returns the space in kilobytes that is available on the file system containing 'path'.
+pub fn free_space(path: &Path) -> Result<u64> {
+    // The POSIX format is one header line followed by 'Filesystem 1024-blocks Used Available
+    // Capacity Mounted-on'.
+    let output = run_command(&["df", "-Pk", "."], path, Verbose::No)?.stdout;
+    match output
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|s| s.parse().ok())
+    {
+        Some(kb) => Ok(kb),
+        None => bail!("Could not parse df output: {}", output),
+    }
+}
+
+/// Packs the shared bzr repository and garbage collects the git mirror.
+pub fn compact(bzr_repo: &Path, git_repo: &Path) -> Result<()> {
+    run_command(&["git", "gc"], git_repo, Verbose::Yes)?;
+    run_command(&["git", "prune"], git_repo, Verbose::Yes)?;
+    run_command(&["bzr", "pack"], bzr_repo, Verbose::Yes)?;
+    Ok(())
+}
+
+/// Returns the sorted names of all branch directories in 'bzr_repo' that are not in 'slugs'.
+pub fn orphaned_dirs(bzr_repo: &Path, slugs: &HashSet<String>) -> Result<Vec<String>> {
+    let mut orphans = branch_dirs(bzr_repo)?
+        .into_iter()
+        .filter(|name| !slugs.contains(name))
+        .collect::<Vec<_>>();
+    orphans.sort();
+    Ok(orphans)
+}
@@ -0,0 +1,114 @@
+use crate::errors::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+const FILE_NAME: &str = "revision_map.json";
+
+/// Translates between bzr revisions and the git commits git-remote-bzr created for them. Stored
+/// as revision_map.json in the data directory.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct RevisionMap {
+    // Git SHA by bzr revision id. Revision ids are unique across branches.
+    shas: HashMap<String, String>,
+    // Revision id by mainline revno, for every branch by unique name.
+    revnos: HashMap<String, BTreeMap<i32, String>>,
+}
+
+/// One bzr revision and its git commit. 'revno' is only known if we looked up a revno or the
+/// revision is on the mainline of the branch that was asked about.
+#[derive(Debug)]
+pub struct Mapping {
+    pub revno: Option<i32>,
+    pub revision_id: String,
+    pub sha: String,
+}
+
+impl RevisionMap {
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(FILE_NAME);
+        if !path.exists() {
+            return Ok(RevisionMap::default());
+        }
+        let file = fs::File::open(&path).chain_err(|| "Could not open revision map.")?;
+        let this = serde_json::from_reader(file).chain_err(|| "Could not parse revision map.")?;
+        Ok(this)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result<()> {
+        let file = fs::File::create(data_dir.join(FILE_NAME))
+            .chain_err(|| "Could not write revision map.")?;
+        serde_json::to_writer(file, self).chain_err(|| "Could not write revision map.")?;
+        Ok(())
+    }
+
+    /// Returns the revision id recorded for 'revno' of 'branch'.
+    pub fn revision_id(&self, branch: &str, revno: i32) -> Option<&str> {
+        self.revnos
+            .get(branch)
+            .and_then(|revnos| revnos.get(&revno))
+            .map(|r| r.as_str())
+    }
+
+    /// Returns the highest revno recorded for 'branch'.
+    pub fn last_revno(&self, branch: &str) -> Option<i32> {
+        self.revnos
+            .get(branch)
+            .and_then(|revnos| revnos.keys().next_back().cloned())
+    }
+
+    pub fn record(&mut self, branch: &str, revno: i32, revision_id: &str, sha: &str) {
+        self.shas.insert(revision_id.to_string(), sha.to_string());
+        self.revnos
+            .entry(branch.to_string())
+            .or_default()
+            .insert(revno, revision_id.to_string());
+    }
+
+    /// Forgets the revnos of 'branch', e.g. because its history was overwritten. The revision ids
+    /// stay valid.
+    pub fn forget_revnos(&mut self, branch: &str) {
+        self.revnos.remove(branch);
+    }
+
+    /// Looks up 'query', which is a revno of 'branch', a bzr revision id or a (possibly
+    /// abbreviated) git SHA. Abbreviated SHAs can match several commits. SHA prefixes can consist
+    /// of digits only, so those are searched if no revno matches.
+    pub fn lookup(&self, branch: &str, query: &str) -> Vec<Mapping> {
+        if let Ok(revno) = query.parse::<i32>() {
+            let mapping = self
+                .revision_id(branch, revno)
+                .and_then(|revision_id| self.mapping(branch, revision_id));
+            if let Some(mapping) = mapping {
+                return vec![mapping];
+            }
+        }
+        if let Some(mapping) = self.mapping(branch, query) {
+            return vec![mapping];
+        }
+        let query = query.to_lowercase();
+        let mut mappings = self
+            .shas
+            .iter()
+            .filter(|(_, sha)| sha.starts_with(&query))
+            .filter_map(|(revision_id, _)| self.mapping(branch, revision_id))
+            .collect::<Vec<_>>();
+        mappings.sort_by(|a, b| a.sha.cmp(&b.sha));
+        mappings
+    }
+
+    fn mapping(&self, branch: &str, revision_id: &str) -> Option<Mapping> {
+        let sha = self.shas.get(revision_id)?;
+        let revno = self.revnos.get(branch).and_then(|revnos| {
+            revnos
+                .iter()
+                .find(|(_, r)| r.as_str() == revision_id)
+                .map(|(revno, _)| *revno)
+        });
+        Some(Mapping {
+            revno,
+            revision_id: revision_id.to_string(),
+            sha: sha.clone(),
+        })
+    }
+}
@@ -1,7 +1,18 @@
 use crate::errors::*;
 use error_chain::bail;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::io::Read;
 use std::path::Path;
 use std::process;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    // Timeouts for commands by the program they run, e.g. "bzr".
+    static ref DEFAULT_TIMEOUTS: Mutex<HashMap<String, Duration>> = Mutex::new(HashMap::new());
+}
 
 #[derive(Debug, PartialEq)]
 pub enum Verbose {
@@ -15,32 +26,171 @@ pub struct Output {
     pub stderr: String,
 }
 
+impl Output {
+    /// Returns the last 'num_lines' lines of stdout followed by stderr.
+    pub fn tail(&self, num_lines: usize) -> String {
+        let lines = self
+            .stdout
+            .lines()
+            .chain(self.stderr.lines())
+            .collect::<Vec<_>>();
+        let start = lines.len().saturating_sub(num_lines);
+        lines[start..].join("\n")
+    }
+}
+
+fn to_string(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|_| "...garbage...".into())
+}
+
+fn print_output(output: &Output) {
+    for line in output.stdout.lines() {
+        println!("    {}", line.trim_end());
+    }
+    for line in output.stderr.lines() {
+        println!("    {}", line.trim_end());
+    }
+}
+
+/// Sets the timeout for all commands that run 'program', unless the caller passes one.
+pub fn set_default_timeout(program: &str, timeout: Duration) {
+    DEFAULT_TIMEOUTS
+        .lock()
+        .unwrap()
+        .insert(program.to_string(), timeout);
+}
+
+/// Runs the command and returns its output. It is killed if it takes longer than the default
+/// timeout of the program, see 'set_default_timeout'.
 pub fn run_command(args: &[&str], cwd: &Path, verbose: Verbose) -> Result<Output> {
+    let timeout = DEFAULT_TIMEOUTS.lock().unwrap().get(args[0]).cloned();
+    run(args, cwd, verbose, timeout)
+}
+
+/// Like 'run_command', but kills the process if it did not finish after 'timeout'.
+pub fn run_command_with_timeout(
+    args: &[&str],
+    cwd: &Path,
+    verbose: Verbose,
+    timeout: Duration,
+) -> Result<Output> {
+    run(args, cwd, verbose, Some(timeout))
+}
+
+/// Puts the child into its own session and process group, so that a timeout also kills the
+/// processes it started, e.g. the ssh spawned by 'git push'. Otherwise they would keep our pipes
+/// open. Without a controlling terminal, ssh fails right away instead of waiting for somebody to
+/// enter a passphrase or confirm a host key.
+#[cfg(unix)]
+fn new_process_group(command: &mut process::Command) {
+    use std::os::unix::process::CommandExt;
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn new_process_group(_: &mut process::Command) {}
+
+#[cfg(unix)]
+fn kill(child: &mut process::Child) {
+    // The child is the leader of its process group, so its pid is the id of the group.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut process::Child) {
+    let _ = child.kill();
+}
+
+fn run(args: &[&str], cwd: &Path, verbose: Verbose, timeout: Option<Duration>) -> Result<Output> {
     let command = args.join(" ");
     if verbose == Verbose::Yes {
-        println!("-> {} [{}]", command, cwd.to_string_lossy());
+        match timeout {
+            Some(timeout) => println!(
+                "-> {} [{}, timeout {}s]",
+                command,
+                cwd.to_string_lossy(),
+                timeout.as_secs()
+            ),
+            None => println!("-> {} [{}]", command, cwd.to_string_lossy()),
+        }
     }
 
-    let mut command = process::Command::new(args[0]);
-    command.args(&args[1..]);
-    command.current_dir(cwd);
-    // This should always run - or the binary was not found, which is indeed fatal.
-    let res = command.output().unwrap();
-    let output = Output {
-        stdout: String::from_utf8(res.stdout).unwrap_or_else(|_| "...garbage...".into()),
-        stderr: String::from_utf8(res.stderr).unwrap_or_else(|_| "...garbage...".into()),
-    };
+    let mut builder = process::Command::new(args[0]);
+    builder
+        .args(&args[1..])
+        .current_dir(cwd)
+        // Nobody could answer a prompt, e.g. for an ssh password. The command should fail instead.
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped());
+    new_process_group(&mut builder);
+    let mut child = builder
+        .spawn()
+        .chain_err(|| format!("Could not run {}.", command))?;
 
-    if verbose == Verbose::Yes {
-        for line in output.stdout.lines() {
-            println!("    {}", line.trim_end());
+    // The pipes must be drained while waiting, or the child blocks once they are full.
+    let mut stdout = child.stdout.take().unwrap();
+    let stdout_reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = stdout.read_to_end(&mut buffer);
+        buffer
+    });
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = stderr.read_to_end(&mut buffer);
+        buffer
+    });
+
+    let start = Instant::now();
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .chain_err(|| "Could not wait for process.")?
+        {
+            break status;
         }
-        for line in output.stderr.lines() {
-            println!("    {}", line.trim_end());
+        let expired = match timeout {
+            Some(timeout) => start.elapsed() > timeout,
+            None => false,
+        };
+        if expired {
+            timed_out = true;
+            kill(&mut child);
+            break child.wait().chain_err(|| "Could not wait for process.")?;
         }
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    let mut output = Output {
+        stdout: to_string(stdout_reader.join().unwrap()),
+        stderr: to_string(stderr_reader.join().unwrap()),
+    };
+    if timed_out {
+        output.stderr.push_str(&format!(
+            "\nKilled after a timeout of {} seconds.\n",
+            timeout.unwrap().as_secs()
+        ));
     }
 
-    if !res.status.success() {
+    if verbose == Verbose::Yes {
+        print_output(&output);
+    }
+
+    if timed_out {
+        bail!(ErrorKind::Timeout(command, timeout.unwrap(), output));
+    }
+    if !status.success() {
         bail!(ErrorKind::ProcessFailed(output));
     }
     Ok(output)
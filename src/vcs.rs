@@ -0,0 +1,545 @@
+use crate::errors::*;
+use crate::launchpad::{parse_bzr_log, LogEntry};
+use crate::subprocess::{run_command, Verbose};
+use error_chain::bail;
+use std::fmt;
+use std::path::Path;
+
+/// A revision of a branch.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Revision {
+    pub revno: i32,
+    pub revision_id: String,
+}
+
+/// A tag of a branch. 'revno' is None if the tagged revision is not on the mainline of the branch.
+#[derive(Debug, Clone)]
+pub struct Tag {
+    pub name: String,
+    pub revno: Option<i32>,
+}
+
+/// The version control operations bunnybot needs on local checkouts of Launchpad branches. All
+/// paths are the root of a checkout.
+pub trait Vcs: fmt::Debug + Sync {
+    /// Creates a checkout of the Launchpad branch 'unique_name' at 'path'.
+    fn branch(&self, unique_name: &str, path: &Path) -> Result<()>;
+
+    /// Creates a checkout at 'path' that is a copy of the checkout at 'source'.
+    fn copy(&self, source: &Path, path: &Path) -> Result<()>;
+
+    /// Makes the checkout identical to its remote branch, even if history was rewritten.
+    fn pull(&self, path: &Path) -> Result<()>;
+
+    fn revno(&self, path: &Path) -> Result<i32>;
+
+    fn revision(&self, path: &Path) -> Result<Revision>;
+
+    fn tags(&self, path: &Path) -> Result<Vec<Tag>>;
+
+    /// Returns at most 'limit' revisions of the mainline, newest first.
+    fn log(&self, path: &Path, limit: usize) -> Result<Vec<LogEntry>>;
+
+    /// Throws away uncommitted changes and unknown files.
+    fn revert(&self, path: &Path) -> Result<()>;
+
+    /// Merges the checkout at 'source' into the working tree without committing. Fails with
+    /// 'ErrorKind::MergeConflicts' if the merge produced conflicts.
+    fn merge(&self, path: &Path, source: &Path) -> Result<()>;
+
+    /// Applies the inverse of the changes introduced by 'revision' to the working tree without
+    /// committing.
+    fn reverse_merge(&self, path: &Path, revision: &Revision) -> Result<()>;
+
+    /// Returns the paths of all versioned files with uncommitted changes.
+    fn changed_files(&self, path: &Path) -> Result<Vec<String>>;
+
+    /// Returns the paths that are in conflict in the working tree.
+    fn conflicts(&self, path: &Path) -> Result<Vec<String>>;
+
+    /// Commits all changes in the working tree.
+    fn commit(&self, path: &Path, message: &str) -> Result<()>;
+
+    /// Pushes to the branch the checkout was created from. Only used for targets, which belong to
+    /// us. Bzr overwrites them, git refuses to drop commits.
+    fn push(&self, path: &Path) -> Result<()>;
+
+    /// Pushes to the Launchpad branch 'unique_name'. Fails if that would discard history there.
+    fn push_to(&self, path: &Path, unique_name: &str) -> Result<()>;
+
+    /// Fetches the Launchpad branch 'unique_name' into the git mirror at 'git_repo' and returns
+    /// the ref it can be found at there.
+    fn fetch_into_mirror(&self, unique_name: &str, slug: &str, git_repo: &Path) -> Result<String>;
+}
+
+/// Turns the error of a failed merge into 'ErrorKind::MergeConflicts' if the merge left conflicts
+/// behind, so that the user learns which files need attention.
+fn merge_error(vcs: &dyn Vcs, path: &Path, err: Error) -> Result<()> {
+    let conflicts = vcs.conflicts(path)?;
+    if conflicts.is_empty() {
+        return Err(err);
+    }
+    bail!(ErrorKind::MergeConflicts(conflicts));
+}
+
+#[derive(Debug)]
+pub struct Bzr;
+
+pub static BZR: Bzr = Bzr;
+
+/// Extracts the paths from the output of 'bzr conflicts', which prints lines like 'Text conflict in
+/// src/foo.cc'. Lines we do not understand are kept verbatim, so that no conflict is swallowed.
+fn parse_bzr_conflicts(output: &str) -> Vec<String> {
+    let mut paths = Vec::new();
+    for line in output.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let path = match line.find(" in ") {
+            Some(idx) => line[idx + 4..].to_string(),
+            None => line.to_string(),
+        };
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+impl Vcs for Bzr {
+    fn branch(&self, unique_name: &str, path: &Path) -> Result<()> {
+        let (parent, name) = split_path(path);
+        run_command(
+            &["bzr", "branch", &format!("lp:{}", unique_name), name],
+            parent,
+            Verbose::Yes,
+        )?;
+        Ok(())
+    }
+
+    fn copy(&self, source: &Path, path: &Path) -> Result<()> {
+        let (parent, name) = split_path(path);
+        run_command(
+            &["bzr", "branch", &source.to_string_lossy(), name],
+            parent,
+            Verbose::Yes,
+        )?;
+        Ok(())
+    }
+
+    fn pull(&self, path: &Path) -> Result<()> {
+        run_command(&["bzr", "pull", "--overwrite"], path, Verbose::Yes)?;
+        Ok(())
+    }
+
+    fn revno(&self, path: &Path) -> Result<i32> {
+        let output = run_command(&["bzr", "revno"], path, Verbose::No)?.stdout;
+        let revno = output.trim().parse().unwrap();
+        Ok(revno)
+    }
+
+    fn revision(&self, path: &Path) -> Result<Revision> {
+        let output = run_command(&["bzr", "revision-info"], path, Verbose::No)?.stdout;
+        let mut parts = output.split_whitespace();
+        let revno = parts.next().and_then(|r| r.parse().ok());
+        let revision_id = parts.next();
+        match (revno, revision_id) {
+            (Some(revno), Some(revision_id)) => Ok(Revision {
+                revno,
+                revision_id: revision_id.to_string(),
+            }),
+            _ => bail!("Could not parse bzr revision-info output: {}", output),
+        }
+    }
+
+    fn tags(&self, path: &Path) -> Result<Vec<Tag>> {
+        let output = run_command(&["bzr", "tags"], path, Verbose::No)?.stdout;
+        // Every line is the tag name followed by the revno, which is dotted for merged revisions
+        // and '?' for revisions that are not in the branch at all.
+        let mut tags = Vec::new();
+        for line in output.lines() {
+            let line = line.trim_end();
+            if let Some(idx) = line.rfind(char::is_whitespace) {
+                tags.push(Tag {
+                    name: line[..idx].trim().to_string(),
+                    revno: line[idx + 1..].parse().ok(),
+                });
+            }
+        }
+        Ok(tags)
+    }
+
+    fn log(&self, path: &Path, limit: usize) -> Result<Vec<LogEntry>> {
+        let output = run_command(
+            &[
+                "bzr",
+                "log",
+                "--long",
+                "--levels=1",
+                "--show-ids",
+                "--limit",
+                &limit.to_string(),
+            ],
+            path,
+            Verbose::No,
+        )?
+        .stdout;
+        Ok(parse_bzr_log(&output))
+    }
+
+    fn revert(&self, path: &Path) -> Result<()> {
+        run_command(&["bzr", "revert"], path, Verbose::Yes)?;
+        run_command(
+            &["bzr", "clean-tree", "--unknown", "--detritus", "--force"],
+            path,
+            Verbose::Yes,
+        )?;
+        Ok(())
+    }
+
+    fn merge(&self, path: &Path, source: &Path) -> Result<()> {
+        match run_command(
+            &["bzr", "merge", &source.to_string_lossy()],
+            path,
+            Verbose::Yes,
+        ) {
+            Ok(_) => Ok(()),
+            // bzr merge exits non-zero when it encountered conflicts.
+            Err(err) => merge_error(self, path, err),
+        }
+    }
+
+    fn reverse_merge(&self, path: &Path, revision: &Revision) -> Result<()> {
+        let range = format!(
+            "revid:{}..before:revid:{}",
+            revision.revision_id, revision.revision_id
+        );
+        match run_command(&["bzr", "merge", "-r", &range, "."], path, Verbose::Yes) {
+            Ok(_) => Ok(()),
+            Err(err) => merge_error(self, path, err),
+        }
+    }
+
+    fn changed_files(&self, path: &Path) -> Result<Vec<String>> {
+        let output = run_command(&["bzr", "status", "--short"], path, Verbose::No)?.stdout;
+        // Every line starts with three columns of status flags, followed by the path. Unknown files
+        // ('?') are not committed, so they do not count as changes.
+        Ok(output
+            .lines()
+            .filter(|line| line.len() > 3 && !line.starts_with('?'))
+            .map(|line| line[3..].trim().to_string())
+            .collect())
+    }
+
+    fn conflicts(&self, path: &Path) -> Result<Vec<String>> {
+        let output = run_command(&["bzr", "conflicts"], path, Verbose::No)?.stdout;
+        Ok(parse_bzr_conflicts(&output))
+    }
+
+    fn commit(&self, path: &Path, message: &str) -> Result<()> {
+        run_command(&["bzr", "commit", "-m", message], path, Verbose::Yes)?;
+        Ok(())
+    }
+
+    fn push(&self, path: &Path) -> Result<()> {
+        run_command(
+            &["bzr", "push", ":parent", "--overwrite"],
+            path,
+            Verbose::Yes,
+        )?;
+        Ok(())
+    }
+
+    fn push_to(&self, path: &Path, unique_name: &str) -> Result<()> {
+        run_command(
+            &["bzr", "push", &format!("lp:{}", unique_name)],
+            path,
+            Verbose::Yes,
+        )?;
+        Ok(())
+    }
+
+    fn fetch_into_mirror(&self, _: &str, slug: &str, git_repo: &Path) -> Result<String> {
+        // git-remote-bzr fetches from the branches in the bzr repository it is told about.
+        run_command(
+            &["git", "config", "remote-bzr.branches", slug],
+            git_repo,
+            Verbose::Yes,
+        )?;
+        run_command(&["git", "fetch", "bzr_origin"], git_repo, Verbose::Yes)?;
+        Ok(format!("refs/remotes/bzr_origin/{}", slug))
+    }
+}
+
+/// Branches in Launchpad git repositories. Their unique name is the unique name of the repository
+/// and the name of the branch, separated by a colon, e.g. '~widelands-dev/widelands/+git/wl:foo'.
+#[derive(Debug)]
+pub struct Git;
+
+pub static GIT: Git = Git;
+
+/// Splits a unique name into the URL of the repository and the name of the branch.
+fn git_location(unique_name: &str) -> Result<(String, &str)> {
+    let mut parts = unique_name.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(repository), Some(branch)) => Ok((
+            format!("git+ssh://git.launchpad.net/{}", repository),
+            branch,
+        )),
+        _ => bail!(
+            "{} does not name a branch in a git repository.",
+            unique_name
+        ),
+    }
+}
+
+impl Vcs for Git {
+    fn branch(&self, unique_name: &str, path: &Path) -> Result<()> {
+        let (url, branch) = git_location(unique_name)?;
+        let (parent, name) = split_path(path);
+        run_command(
+            &["git", "clone", "--branch", branch, &url, name],
+            parent,
+            Verbose::Yes,
+        )?;
+        Ok(())
+    }
+
+    fn copy(&self, source: &Path, path: &Path) -> Result<()> {
+        let (parent, name) = split_path(path);
+        run_command(
+            &["git", "clone", &source.to_string_lossy(), name],
+            parent,
+            Verbose::Yes,
+        )?;
+        Ok(())
+    }
+
+    fn pull(&self, path: &Path) -> Result<()> {
+        run_command(&["git", "fetch", "origin"], path, Verbose::Yes)?;
+        run_command(
+            &["git", "reset", "--hard", "@{upstream}"],
+            path,
+            Verbose::Yes,
+        )?;
+        Ok(())
+    }
+
+    fn revno(&self, path: &Path) -> Result<i32> {
+        let output = run_command(
+            &["git", "rev-list", "--first-parent", "--count", "HEAD"],
+            path,
+            Verbose::No,
+        )?
+        .stdout;
+        let revno = output.trim().parse().unwrap();
+        Ok(revno)
+    }
+
+    fn revision(&self, path: &Path) -> Result<Revision> {
+        let output = run_command(&["git", "rev-parse", "HEAD"], path, Verbose::No)?.stdout;
+        Ok(Revision {
+            revno: self.revno(path)?,
+            revision_id: output.trim().to_string(),
+        })
+    }
+
+    fn tags(&self, path: &Path) -> Result<Vec<Tag>> {
+        let output = run_command(&["git", "tag", "--merged", "HEAD"], path, Verbose::No)?.stdout;
+        let mainline = run_command(
+            &["git", "rev-list", "--first-parent", "HEAD"],
+            path,
+            Verbose::No,
+        )?
+        .stdout
+        .lines()
+        .map(|l| l.to_string())
+        .collect::<Vec<_>>();
+        let mut tags = Vec::new();
+        for name in output.lines() {
+            let sha = run_command(
+                &["git", "rev-parse", &format!("{}^{{commit}}", name)],
+                path,
+                Verbose::No,
+            )?
+            .stdout;
+            // The mainline is listed newest first, the oldest revision has revno 1.
+            let revno = mainline
+                .iter()
+                .position(|s| s == sha.trim())
+                .map(|idx| (mainline.len() - idx) as i32);
+            tags.push(Tag {
+                name: name.to_string(),
+                revno,
+            });
+        }
+        Ok(tags)
+    }
+
+    fn log(&self, path: &Path, limit: usize) -> Result<Vec<LogEntry>> {
+        let output = run_command(
+            &[
+                "git",
+                "log",
+                "--first-parent",
+                "--max-count",
+                &limit.to_string(),
+                "--format=%H%x00%an%x00%s",
+            ],
+            path,
+            Verbose::No,
+        )?
+        .stdout;
+        let mut revno = self.revno(path)?;
+        let mut entries = Vec::new();
+        for line in output.lines() {
+            let mut parts = line.splitn(3, '\0');
+            let revision_id = parts.next().unwrap_or_default().to_string();
+            let author = parts.next().unwrap_or_default().to_string();
+            let summary = parts.next().unwrap_or_default().to_string();
+            entries.push(LogEntry {
+                revno,
+                revision_id,
+                author,
+                summary,
+            });
+            revno -= 1;
+        }
+        Ok(entries)
+    }
+
+    fn revert(&self, path: &Path) -> Result<()> {
+        run_command(&["git", "reset", "--hard"], path, Verbose::Yes)?;
+        run_command(&["git", "clean", "-fd"], path, Verbose::Yes)?;
+        Ok(())
+    }
+
+    fn merge(&self, path: &Path, source: &Path) -> Result<()> {
+        run_command(
+            &["git", "fetch", &source.to_string_lossy(), "HEAD"],
+            path,
+            Verbose::Yes,
+        )?;
+        match run_command(
+            &["git", "merge", "--no-ff", "--no-commit", "FETCH_HEAD"],
+            path,
+            Verbose::Yes,
+        ) {
+            Ok(_) => Ok(()),
+            Err(err) => merge_error(self, path, err),
+        }
+    }
+
+    fn reverse_merge(&self, path: &Path, revision: &Revision) -> Result<()> {
+        match run_command(
+            &[
+                "git",
+                "revert",
+                "--no-commit",
+                "--mainline",
+                "1",
+                &revision.revision_id,
+            ],
+            path,
+            Verbose::Yes,
+        ) {
+            Ok(_) => Ok(()),
+            Err(err) => merge_error(self, path, err),
+        }
+    }
+
+    fn changed_files(&self, path: &Path) -> Result<Vec<String>> {
+        let output = run_command(&["git", "status", "--porcelain"], path, Verbose::No)?.stdout;
+        // Every line starts with two columns of status flags and a space, followed by the path.
+        // Untracked files ('??') are not committed.
+        Ok(output
+            .lines()
+            .filter(|line| line.len() > 3 && !line.starts_with("??"))
+            .map(|line| line[3..].trim().to_string())
+            .collect())
+    }
+
+    fn conflicts(&self, path: &Path) -> Result<Vec<String>> {
+        let output = run_command(
+            &["git", "diff", "--name-only", "--diff-filter=U"],
+            path,
+            Verbose::No,
+        )?
+        .stdout;
+        Ok(output.lines().map(|l| l.trim().to_string()).collect())
+    }
+
+    fn commit(&self, path: &Path, message: &str) -> Result<()> {
+        // Only tracked files, so that build artifacts of the verification stay out. Merges stage
+        // the files they add.
+        run_command(&["git", "commit", "-a", "-m", message], path, Verbose::Yes)?;
+        Ok(())
+    }
+
+    fn push(&self, path: &Path) -> Result<()> {
+        // Without force, so that we never drop commits somebody else pushed in the meantime.
+        run_command(&["git", "push", "origin", "HEAD"], path, Verbose::Yes)?;
+        Ok(())
+    }
+
+    fn push_to(&self, path: &Path, unique_name: &str) -> Result<()> {
+        let (url, branch) = git_location(unique_name)?;
+        run_command(
+            &["git", "push", &url, &format!("HEAD:refs/heads/{}", branch)],
+            path,
+            Verbose::Yes,
+        )?;
+        Ok(())
+    }
+
+    fn fetch_into_mirror(&self, unique_name: &str, slug: &str, git_repo: &Path) -> Result<String> {
+        let (url, branch) = git_location(unique_name)?;
+        let reference = format!("refs/remotes/launchpad/{}", slug);
+        run_command(
+            &[
+                "git",
+                "fetch",
+                "--no-tags",
+                &url,
+                &format!("+refs/heads/{}:{}", branch, reference),
+            ],
+            git_repo,
+            Verbose::Yes,
+        )?;
+        Ok(reference)
+    }
+}
+
+/// Returns the parent directory and the file name of 'path', so that commands creating 'path'
+/// can run in its parent.
+fn split_path(path: &Path) -> (&Path, &str) {
+    let parent = path
+        .parent()
+        .expect("Checkouts are never the root directory.");
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .expect("Checkout names are always valid UTF-8.");
+    (parent, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn git_locations() {
+        let (url, branch) = git_location("~widelands-dev/widelands/+git/widelands:master").unwrap();
+        assert_eq!(
+            url,
+            "git+ssh://git.launchpad.net/~widelands-dev/widelands/+git/widelands"
+        );
+        assert_eq!(branch, "master");
+        // Only the first colon separates the branch, which may contain more of them.
+        let (url, branch) = git_location("~a/widelands/+git/wl:foo:bar").unwrap();
+        assert_eq!(url, "git+ssh://git.launchpad.net/~a/widelands/+git/wl");
+        assert_eq!(branch, "foo:bar");
+        assert!(git_location("~a/widelands/foo").is_err());
+    }
+}